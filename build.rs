@@ -1,3 +1,14 @@
 fn main() {
     lalrpop::process_root().unwrap();
+    // the device runtime, for the differential tests in diff.rs. a build script
+    // cannot tell test builds apart, so the library is always built, but only
+    // the cfg(test) diff module refers to it and the linker leaves it out of
+    // the binary otherwise
+    cc::Build::new()
+        .file("emfrp.c")
+        .define("EMFRP_LIBRARY", None)
+        .flag("-fwrapv") // ints wrap around as in the Rust machine
+        .compile("emfrp");
+    println!("cargo:rerun-if-changed=emfrp.c");
+    println!("cargo:rerun-if-changed=src/grammer.lalrpop");
 }
@@ -1,8 +1,23 @@
 #include <stdio.h>
 #include <stdlib.h>
+#include <string.h>
 #define MAX_NODE_SIZE 128
+#ifndef EMFRP_LIBRARY // build.rs links this file into the host for differential tests
 #define DEBUG
+#endif
+#define FORMAT_VERSION 2
+#define OPCODE_SET_VERSION 2 // must match container.rs
+#define HEADER_SIZE 16
+#define SECTION_ENTRY_SIZE 12
+#define SYNC 0xA5 // serial framing, see link.rs
+#define MAX_PAYLOAD 256
+#define MAX_UPLOAD 16384 // largest program taken, see link.rs
+#define MAX_DRIVERS 16
+#define DRIVER_ENTRY_SIZE 8
+#define DEFAULT_TICK_MS 1000 // UPD_FREQUENCY_MS in main.rs
 typedef unsigned char uint8_t;
+typedef signed char int8_t;
+typedef unsigned int uint32_t;
 typedef union
 {
     void *ptr;
@@ -38,9 +53,48 @@ enum bytecode
     BC_Call = 24,
     BC_Exit = 25,
     BC_Halt = 26,
+    BC_GetFunc = 27,
+    BC_GetData = 28,
+    BC_FreeNode = 29,
 
 };
 
+enum section_kind
+{
+    SECTION_INIT = 1,
+    SECTION_UPDATE = 2,
+    // function and data tables are only read by the host
+    SECTION_STRINGS = 5,
+    SECTION_DRIVERS = 6, // node, kind, name in SECTION_STRINGS, sampling period
+    SECTION_DEPS = 7,    // node, always, number of users, users
+};
+enum driver_kind
+{
+    DRIVER_INPUT = 1,
+    DRIVER_OUTPUT = 2,
+};
+typedef enum load_result_t
+{
+    LOAD_OK,
+    LOAD_BAD_MAGIC,
+    LOAD_BAD_VERSION,
+    LOAD_BAD_LENGTH,
+    LOAD_BAD_CRC,
+    LOAD_ERROR, // the program cannot run, e.g. init failed
+} load_result_t;
+enum frame_kind
+{
+    FRAME_BEGIN = 1,
+    FRAME_DATA = 2,
+    FRAME_ACK = 3,
+    FRAME_NAK = 4,
+    FRAME_RESULT = 5,
+    FRAME_TELEMETRY = 6, // see telemetry.rs
+    FRAME_RESET = 7,
+};
+#define TELEMETRY_HEADER_SIZE 8
+#define TELEMETRY_ENTRY_SIZE 6
+#define VALUE_INT 1 // the runtime does not know types, so bools are sent as ints
 typedef enum exec_result_t
 {
     OK,
@@ -72,16 +126,49 @@ typedef struct node_t
     int v;
     output_action_t o_action;
     input_action_t i_action;
+    uint32_t every_ms; // a DEV input is read at most this often, 0 for every cycle
+    uint32_t next_ms;
+    uint8_t always; // with on-change updates: reads a driver or a value @last
+    uint8_t dirty;  // updated in this cycle
+    uint8_t nusers;
+    uint8_t *users; // updated again when this node changes
     struct node_t *next;
 } node_t;
+typedef struct func_t
+{
+    uint8_t *insns;
+    struct func_t *next;
+} func_t;
+typedef struct data_t
+{
+    value_t v;
+    struct data_t *next;
+} data_t;
+typedef struct driver_t
+{
+    const char *name; // of the node it is attached to
+    uint8_t kind;
+    void (*f)(int *);
+} driver_t;
 void set_input_action(int node_index, dev_input_t driver);
 void set_output_action(int node_index, dev_output_t driver);
 exec_result_t emfrp_exec(uint8_t *p);
-void emfrp_set_new_code(uint8_t *p);
+load_result_t emfrp_set_new_code(uint8_t *p, int len);
+int emfrp_node_count(void);
 
 static uint8_t *update;
 static value_t stack[128];
 static node_t *nodes_head, *nodes_tail;
+static func_t *funcs_head, *funcs_tail;
+static data_t *datas_head, *datas_tail;
+static driver_t drivers[MAX_DRIVERS];
+static int ndrivers;
+static uint32_t cycle;
+static uint32_t tick_ms = DEFAULT_TICK_MS; // from the header of the code
+static uint32_t now_ms;                    // advanced by a tick on every update
+static uint8_t *deps;                      // copy of SECTION_DEPS
+static int on_change;                      // the program came with dependencies
+static int telemetry_full; // every node is reported after a load
 int next_int(uint8_t **p)
 { // little endian
     int ret = (int)(**p) + (((int)(p[0][1])) << 8) + (((int)(p[0][2])) << 16) + (((int)(p[0][3])) << 24);
@@ -103,12 +190,281 @@ node_t *node_b(uint8_t n)
     }
     return p;
 }
+func_t *func_b(uint8_t n)
+{
+    func_t *p = funcs_head;
+    for (uint8_t i = 0; i < n; i++)
+    {
+        p = p->next;
+    }
+    return p;
+}
+data_t *data_b(uint8_t n)
+{
+    data_t *p = datas_head;
+    for (uint8_t i = 0; i < n; i++)
+    {
+        p = p->next;
+    }
+    return p;
+}
+uint8_t *copy_insns(uint8_t **p, int len)
+{
+    uint8_t *ret = (uint8_t *)malloc(len);
+    for (int i = 0; i < len; ++i)
+    {
+        ret[i] = **p;
+        ++*p;
+    }
+    return ret;
+}
 
 void set_input_action(int node_index, dev_input_t driver)
 {
+    node_t *nd = node_b(node_index);
+    if (nd->i_action.kind == INSN)
+        free(nd->i_action.insns);
+    nd->i_action.kind = DEV;
+    nd->i_action.dev = driver;
+    nd->every_ms = 0;
+}
+// the driver is read on the next update, then at most every every_ms
+void set_input_period(int node_index, uint32_t every_ms)
+{
+    node_t *nd = node_b(node_index);
+    nd->every_ms = every_ms;
+    nd->next_ms = now_ms;
+}
+static int sample_due(node_t *nd)
+{
+    if (nd->every_ms == 0)
+        return 1;
+    if (now_ms < nd->next_ms)
+        return 0;
+    while (nd->next_ms <= now_ms) // see SECTION_DRIVERS in src/container.rs
+        nd->next_ms += nd->every_ms;
+    return 1;
 }
 void set_output_action(int node_index, dev_output_t driver)
 {
+    node_b(node_index)->o_action = driver;
+}
+// drivers are attached by name to the I/O nodes of every program loaded later.
+// the name is not copied
+static void register_driver(const char *name, uint8_t kind, void (*f)(int *))
+{
+    for (int i = 0; i < ndrivers; ++i)
+    {
+        if (drivers[i].kind == kind && strcmp(drivers[i].name, name) == 0)
+        {
+            drivers[i].f = f;
+            return;
+        }
+    }
+    if (ndrivers < MAX_DRIVERS)
+    {
+        drivers[ndrivers].name = name;
+        drivers[ndrivers].kind = kind;
+        drivers[ndrivers].f = f;
+        ++ndrivers;
+    }
+}
+void emfrp_register_input(const char *name, dev_input_t f)
+{
+    register_driver(name, DRIVER_INPUT, f);
+}
+void emfrp_register_output(const char *name, dev_output_t f)
+{
+    register_driver(name, DRIVER_OUTPUT, f);
+}
+// the n-th NUL terminated string of the pool, or NULL
+static const char *string_at(uint8_t *strings, int len, int n)
+{
+    int i = 0;
+    if (len == 0 || strings[len - 1] != 0)
+        return NULL;
+    for (; n > 0 && i < len; ++i)
+    {
+        if (strings[i] == 0)
+            --n;
+    }
+    return i < len ? (const char *)strings + i : NULL;
+}
+static void attach_drivers(uint8_t *p, int len, uint8_t *strings, int strings_len)
+{
+    for (; len >= DRIVER_ENTRY_SIZE; p += DRIVER_ENTRY_SIZE, len -= DRIVER_ENTRY_SIZE)
+    {
+        const char *name = string_at(strings, strings_len, p[2] | (p[3] << 8));
+        if (name == NULL)
+            continue;
+        for (int i = 0; i < ndrivers; ++i)
+        {
+            if (drivers[i].kind != p[1] || strcmp(drivers[i].name, name) != 0)
+                continue;
+            if (p[1] == DRIVER_INPUT)
+            {
+                set_input_action(p[0], drivers[i].f);
+                set_input_period(p[0], p[4] | (p[5] << 8) | (p[6] << 16) | ((uint32_t)p[7] << 24));
+            }
+            else
+                set_output_action(p[0], drivers[i].f);
+        }
+    }
+}
+// whether every node and user in the entries is one of the first nnodes
+static int deps_valid(uint8_t *p, int len, int nnodes)
+{
+    for (; len >= 3 && len >= 3 + p[2]; len -= 3 + p[2], p += 3 + p[2])
+    {
+        if (p[0] >= nnodes)
+            return 0;
+        for (int i = 0; i < p[2]; ++i)
+            if (p[3 + i] >= nnodes)
+                return 0;
+    }
+    return 1;
+}
+// see SECTION_DEPS in src/container.rs. the section has been checked with
+// deps_valid
+static void set_deps(uint8_t *p, int len)
+{
+    node_t *nd;
+    free(deps);
+    deps = NULL;
+    on_change = len > 0;
+    for (nd = nodes_head; nd != NULL; nd = nd->next)
+    {
+        nd->always = 0;
+        nd->dirty = 1;
+        nd->nusers = 0;
+        nd->users = NULL;
+    }
+    if (!on_change)
+        return;
+    deps = (uint8_t *)malloc(len);
+    memcpy(deps, p, len);
+    for (p = deps; len >= 3 && len >= 3 + p[2]; len -= 3 + p[2], p += 3 + p[2])
+    {
+        nd = node_b(p[0]);
+        nd->always = p[1];
+        nd->nusers = p[2];
+        nd->users = p + 3;
+    }
+}
+// what init changes, so that a failed load leaves the previous program as it
+// was. the bodies that init replaces are kept until the load succeeds
+typedef struct saved_t
+{
+    int nnodes, nfuncs, ndatas;
+    node_t *nodes;
+    uint8_t **funcs;
+    value_t *datas;
+} saved_t;
+static saved_t save(void)
+{
+    saved_t s = {0, 0, 0, NULL, NULL, NULL};
+    for (node_t *nd = nodes_head; nd != NULL; nd = nd->next)
+        ++s.nnodes;
+    for (func_t *fn = funcs_head; fn != NULL; fn = fn->next)
+        ++s.nfuncs;
+    for (data_t *d = datas_head; d != NULL; d = d->next)
+        ++s.ndatas;
+    s.nodes = (node_t *)malloc(s.nnodes * sizeof(node_t));
+    s.funcs = (uint8_t **)malloc(s.nfuncs * sizeof(uint8_t *));
+    s.datas = (value_t *)malloc(s.ndatas * sizeof(value_t));
+    int i = 0;
+    for (node_t *nd = nodes_head; nd != NULL; nd = nd->next)
+        s.nodes[i++] = *nd;
+    i = 0;
+    for (func_t *fn = funcs_head; fn != NULL; fn = fn->next)
+        s.funcs[i++] = fn->insns;
+    i = 0;
+    for (data_t *d = datas_head; d != NULL; d = d->next)
+        s.datas[i++] = d->v;
+    return s;
+}
+static uint8_t *node_insns(node_t *nd)
+{
+    return nd->i_action.kind == INSN ? nd->i_action.insns : NULL;
+}
+// frees the bodies that the load replaced
+static void commit(saved_t *s)
+{
+    node_t *nd = nodes_head;
+    for (int i = 0; i < s->nnodes; ++i, nd = nd->next)
+        if (node_insns(&s->nodes[i]) != node_insns(nd))
+            free(node_insns(&s->nodes[i]));
+    func_t *fn = funcs_head;
+    for (int i = 0; i < s->nfuncs; ++i, fn = fn->next)
+        if (s->funcs[i] != fn->insns)
+            free(s->funcs[i]);
+    free(s->nodes);
+    free(s->funcs);
+    free(s->datas);
+}
+// frees what the load added and puts back what it replaced
+static void rollback(saved_t *s)
+{
+    node_t *nd = nodes_head, *last_nd = NULL;
+    for (int i = 0; i < s->nnodes; ++i, last_nd = nd, nd = nd->next)
+    {
+        if (node_insns(&s->nodes[i]) != node_insns(nd))
+            free(node_insns(nd));
+        node_t *next = nd->next;
+        *nd = s->nodes[i];
+        nd->next = next;
+    }
+    while (nd != NULL)
+    {
+        node_t *next = nd->next;
+        free(node_insns(nd));
+        free(nd);
+        nd = next;
+    }
+    if (last_nd != NULL)
+        last_nd->next = NULL;
+    else
+        nodes_head = NULL;
+    nodes_tail = last_nd;
+
+    func_t *fn = funcs_head, *last_fn = NULL;
+    for (int i = 0; i < s->nfuncs; ++i, last_fn = fn, fn = fn->next)
+    {
+        if (s->funcs[i] != fn->insns)
+            free(fn->insns);
+        fn->insns = s->funcs[i];
+    }
+    while (fn != NULL)
+    {
+        func_t *next = fn->next;
+        free(fn->insns);
+        free(fn);
+        fn = next;
+    }
+    if (last_fn != NULL)
+        last_fn->next = NULL;
+    else
+        funcs_head = NULL;
+    funcs_tail = last_fn;
+
+    data_t *d = datas_head, *last_d = NULL;
+    for (int i = 0; i < s->ndatas; ++i, last_d = d, d = d->next)
+        d->v = s->datas[i];
+    while (d != NULL)
+    {
+        data_t *next = d->next;
+        free(d);
+        d = next;
+    }
+    if (last_d != NULL)
+        last_d->next = NULL;
+    else
+        datas_head = NULL;
+    datas_tail = last_d;
+
+    free(s->nodes);
+    free(s->funcs);
+    free(s->datas);
 }
 void print_node(char *s)
 {
@@ -124,10 +480,14 @@ exec_result_t emfrp_exec(uint8_t *p)
     value_t *rsp = &stack[0];
     value_t *tmp_v;
     node_t *tmp_nd;
+    func_t *tmp_fn;
+    data_t *tmp_data;
     uint8_t tmp_byte;
     uint8_t *tmp_byte_p;
     int tmp_int;
 
+    if (p == NULL) // no program loaded or just reset
+        return OK;
     while (1)
     {
 #ifdef DEBUG
@@ -150,6 +510,7 @@ exec_result_t emfrp_exec(uint8_t *p)
         case BC_Bool:
             ++p;
             rsp->num = next_byte(&p);
+            ++rsp;
             break;
         case BC_Add: // a b rsp -> (a+b) rsp
             --rsp;
@@ -161,32 +522,38 @@ exec_result_t emfrp_exec(uint8_t *p)
             (rsp - 1)->num *= rsp->num;
             ++p;
             break;
-        case BC_J8:
+        case BC_J8: // offsets are from the end of the instruction
             ++p;
             tmp_byte = next_byte(&p);
-            p += tmp_byte;
+            p += (int8_t)tmp_byte;
+            break;
+        case BC_J32:
+            ++p;
+            tmp_int = next_int(&p);
+            p += tmp_int;
             break;
         case BC_Je8:
             --rsp;
-
+            ++p;
+            tmp_byte = next_byte(&p);
             if (rsp->num)
-            {
-                ++p;
-                tmp_byte = next_byte(&p);
-                p += tmp_byte;
-            }
-            else
-            {
-                p += 2;
-            }
+                p += (int8_t)tmp_byte;
+            break;
+        case BC_Je32:
+            --rsp;
+            ++p;
+            tmp_int = next_int(&p);
+            if (rsp->num)
+                p += tmp_int;
             break;
         case BC_AllocNode: // ALLOCNODE offset insnlen insns
             ++p;
             --rsp;
             tmp_byte = next_byte(&p); // node offset
             tmp_int = next_int(&p);   // insnlen
-            tmp_nd = node_b(tmp_byte);
-            free(tmp_nd->i_action.insns);
+            tmp_nd = node_b(tmp_byte); // the old body is freed once the load succeeds
+            tmp_nd->i_action.kind = INSN; // drivers are attached again after init
+            tmp_nd->o_action = NULL;
             tmp_nd->v = rsp->num; // 前回の値を引き継ぐかどうか
             tmp_byte_p = (uint8_t *)malloc(tmp_int);
             for (int i = 0; i < tmp_int; ++i)
@@ -201,11 +568,11 @@ exec_result_t emfrp_exec(uint8_t *p)
             --rsp;
             tmp_int = next_int(&p); //
             tmp_nd = (node_t *)malloc(sizeof(node_t));
-            tmp_nd->i_action.insns = (uint8_t *)malloc(sizeof(tmp_int));
             tmp_nd->i_action.kind = INSN;
             tmp_nd->o_action = NULL;
             tmp_nd->next = NULL;
             tmp_nd->v = rsp->num;
+            tmp_nd->vlast = 0;
             tmp_byte_p = (uint8_t *)malloc(tmp_int);
             for (int i = 0; i < tmp_int; ++i)
             {
@@ -220,34 +587,113 @@ exec_result_t emfrp_exec(uint8_t *p)
             else
             {
                 nodes_tail->next = tmp_nd;
+                nodes_tail = tmp_nd;
             }
             break;
+        case BC_AllocFunc: // ALLOCFUNC offset insnlen insns
+            ++p;
+            tmp_byte = next_byte(&p);
+            tmp_int = next_int(&p);
+            tmp_fn = func_b(tmp_byte); // the old body is freed once the load succeeds
+            tmp_fn->insns = copy_insns(&p, tmp_int);
+            break;
+        case BC_AllocFuncNew:
+            ++p;
+            tmp_int = next_int(&p);
+            tmp_fn = (func_t *)malloc(sizeof(func_t));
+            tmp_fn->next = NULL;
+            tmp_fn->insns = copy_insns(&p, tmp_int);
+            if (funcs_tail == NULL)
+            {
+                funcs_head = funcs_tail = tmp_fn;
+            }
+            else
+            {
+                funcs_tail->next = tmp_fn;
+                funcs_tail = tmp_fn;
+            }
+            break;
+        case BC_GetFunc:
+            ++p;
+            rsp->num = next_byte(&p);
+            ++rsp;
+            break;
+        case BC_AllocData: // ALLOCDATA offset
+            ++p;
+            --rsp;
+            data_b(next_byte(&p))->v = *rsp;
+            break;
+        case BC_AllocDataNew:
+            ++p;
+            --rsp;
+            tmp_data = (data_t *)malloc(sizeof(data_t));
+            tmp_data->next = NULL;
+            tmp_data->v = *rsp;
+            if (datas_tail == NULL)
+            {
+                datas_head = datas_tail = tmp_data;
+            }
+            else
+            {
+                datas_tail->next = tmp_data;
+                datas_tail = tmp_data;
+            }
+            break;
+        case BC_GetData:
+            ++p;
+            *rsp = data_b(next_byte(&p))->v;
+            ++rsp;
+            break;
+        case BC_Call: // args f rsp -> args rbp rip rsp
+            ++p;
+            tmp_byte = next_byte(&p); // nargs
+            --rsp;
+            tmp_fn = func_b(rsp->num);
+            tmp_v = rsp - tmp_byte;
+            rsp->ptr = (void *)rbp;
+            (rsp + 1)->ptr = (void *)p;
+            rsp += 2;
+            rbp = tmp_v;
+            p = tmp_fn->insns;
+            break;
         case BC_GetLocal:
             ++p;
-            *rsp = *((value_t *)rbp->ptr + next_byte(&p));
+            *rsp = rbp[next_byte(&p)];
             ++rsp;
             break;
 
         case BC_SetLocal:
             --rsp;
             ++p;
-            *((value_t *)rbp->ptr + next_byte(&p)) = *rsp;
+            rbp[next_byte(&p)] = *rsp;
             break;
         case BC_UpdateNode:
             ++p;
             tmp_byte = next_byte(&p);
 
             tmp_nd = node_b(tmp_byte);
+            if (on_change && !tmp_nd->dirty)
+            {
+                rsp->num = tmp_nd->v;
+                ++rsp;
+                break;
+            }
             switch (tmp_nd->i_action.kind)
             {
-            case DEV:
-                tmp_nd->i_action.dev(&tmp_nd->v);
+            case DEV: // the driver updates a copy in place, which SetNode stores
+                rsp->num = tmp_nd->v;
+                if (sample_due(tmp_nd))
+                    tmp_nd->i_action.dev(&rsp->num);
+                ++rsp;
                 break;
-            case ACTION_NONE:
+            case ACTION_NONE: // nil, as SetNode pops a value
+                rsp->ptr = 0;
+                ++rsp;
                 break;
-            case INSN:
+            case INSN: // a node body is a call without arguments
                 rsp->ptr = (void *)rbp;
                 (rsp + 1)->ptr = (void *)p;
+                rbp = rsp;
                 rsp += 2;
                 p = tmp_nd->i_action.insns;
                 break;
@@ -257,7 +703,17 @@ exec_result_t emfrp_exec(uint8_t *p)
             --rsp;
             ++p;
             tmp_byte = next_byte(&p);
-            node_b(tmp_byte)->v = rsp->num;
+            tmp_nd = node_b(tmp_byte);
+            if (on_change && !tmp_nd->dirty)
+                break;
+            if (on_change && tmp_nd->v != rsp->num)
+            {
+                for (int i = 0; i < tmp_nd->nusers; ++i)
+                    node_b(tmp_nd->users[i])->dirty = 1;
+            }
+            tmp_nd->v = rsp->num;
+            if (tmp_nd->o_action != NULL)
+                tmp_nd->o_action(&tmp_nd->v);
             break;
         case BC_GetNode:
             ++p;
@@ -278,12 +734,19 @@ exec_result_t emfrp_exec(uint8_t *p)
             }
             ++p;
             break;
-        case BC_Return: // rbp rip ret_val rsp
-            rsp -= 2;
-            rbp->ptr = (value_t *)(rsp - 1)->ptr;
-            p = (uint8_t *)rsp->ptr;
-            *(rsp - 1) = *(rsp + 1);
-
+        case BC_FreeNode: // the node stays in the list so that indices do not move
+            ++p;
+            tmp_nd = node_b(next_byte(&p)); // the body is freed once the load succeeds
+            tmp_nd->i_action.kind = ACTION_NONE;
+            tmp_nd->o_action = NULL;
+            tmp_nd->v = tmp_nd->vlast = 0;
+            break;
+        case BC_Return: // args rbp rip ret_val rsp -> ret_val rsp
+            tmp_v = rbp;
+            p = (uint8_t *)(rsp - 2)->ptr;
+            rbp = (value_t *)(rsp - 3)->ptr;
+            *tmp_v = *(rsp - 1);
+            rsp = tmp_v + 1;
             break;
         case BC_Halt:
             if (rsp == &stack[0])
@@ -302,30 +765,105 @@ exec_result_t emfrp_exec(uint8_t *p)
         default:
             return TODO;
         }
-#ifdef DEBUG
         if ((rsp - &stack[0]) < 0 || 128 <= (rsp - &stack[0]))
         {
             return PANIC;
         }
-#endif
     }
 }
 
-void emfrp_set_new_code(uint8_t *code)
+uint32_t crc32(uint8_t *p, int len)
+{ // CRC-32/ISO-HDLC, bitwise to save the table
+    uint32_t crc = 0xFFFFFFFF;
+    for (int i = 0; i < len; ++i)
+    {
+        crc ^= p[i];
+        for (int j = 0; j < 8; ++j)
+        {
+            crc = (crc & 1) ? (crc >> 1) ^ 0xEDB88320 : crc >> 1;
+        }
+    }
+    return ~crc;
+}
+// code is the container built by Image::encode (container.rs)
+load_result_t emfrp_set_new_code(uint8_t *code, int len)
 {
-    int init_len = next_int(&code);
-    int upd_len = next_int(&code);
+    uint8_t *p = code;
+    uint8_t *init_p = NULL, *upd_p = NULL, *strings_p = NULL, *drivers_p = NULL, *deps_p = NULL;
+    int init_len = 0, upd_len = 0, strings_len = 0, drivers_len = 0, deps_len = 0;
+    exec_result_t res = OK;
+    if (len < HEADER_SIZE + 4)
+        return LOAD_BAD_LENGTH;
+    if (code[0] != 'E' || code[1] != 'M' || code[2] != 'F' || code[3] != 'R')
+        return LOAD_BAD_MAGIC;
+    if (code[4] != FORMAT_VERSION || code[5] != OPCODE_SET_VERSION)
+        return LOAD_BAD_VERSION;
+    p = code + 8;
+    if (next_int(&p) != len || HEADER_SIZE + code[6] * SECTION_ENTRY_SIZE + 4 > len)
+        return LOAD_BAD_LENGTH;
+    p = code + len - 4;
+    if ((uint32_t)next_int(&p) != crc32(code, len - 4))
+        return LOAD_BAD_CRC;
+    p = code + 12;
+    uint32_t new_tick_ms = next_int(&p);
+    for (int i = 0; i < code[6]; ++i)
+    {
+        p = code + HEADER_SIZE + i * SECTION_ENTRY_SIZE;
+        uint8_t kind = p[0];
+        p += 4;
+        int offset = next_int(&p);
+        int section_len = next_int(&p);
+        if (offset < 0 || section_len < 0 || offset + section_len > len - 4)
+            return LOAD_BAD_LENGTH;
+        switch (kind)
+        {
+        case SECTION_INIT:
+            init_p = code + offset;
+            init_len = section_len;
+            break;
+        case SECTION_UPDATE:
+            upd_p = code + offset;
+            upd_len = section_len;
+            break;
+        case SECTION_STRINGS:
+            strings_p = code + offset;
+            strings_len = section_len;
+            break;
+        case SECTION_DRIVERS:
+            drivers_p = code + offset;
+            drivers_len = section_len;
+            break;
+        case SECTION_DEPS:
+            deps_p = code + offset;
+            deps_len = section_len;
+            break;
+        default:
+            break;
+        }
+    }
 
+    saved_t saved = save();
     if (init_len != 0)
     {
-        emfrp_exec(code);
+        res = emfrp_exec(init_p);
 #ifdef DEBUG
         printf("\n\n");
 #endif
     }
+    if (res == OK && !deps_valid(deps_p, deps_len, emfrp_node_count()))
+        res = RUNTIME_ERR;
+    if (res != OK)
+    { // the previous program keeps running
+        rollback(&saved);
+        return LOAD_ERROR;
+    }
+    commit(&saved);
+    tick_ms = new_tick_ms != 0 ? new_tick_ms : DEFAULT_TICK_MS;
+    attach_drivers(drivers_p, drivers_len, strings_p, strings_len);
+    set_deps(deps_p, deps_len);
     if (upd_len != 0)
     {
-        code += init_len;
+        code = upd_p;
         if (update != NULL)
             free(update);
         uint8_t *upd = (uint8_t *)malloc(upd_len);
@@ -335,15 +873,226 @@ void emfrp_set_new_code(uint8_t *code)
         }
         update = upd;
     }
+    telemetry_full = 1;
+    return LOAD_OK;
+}
+// drops every node, function and data, as if the board had been restarted
+void emfrp_reset(void)
+{
+    while (nodes_head != NULL)
+    {
+        node_t *nd = nodes_head;
+        nodes_head = nd->next;
+        if (nd->i_action.kind == INSN)
+            free(nd->i_action.insns);
+        free(nd);
+    }
+    while (funcs_head != NULL)
+    {
+        func_t *fn = funcs_head;
+        funcs_head = fn->next;
+        free(fn->insns);
+        free(fn);
+    }
+    while (datas_head != NULL)
+    {
+        data_t *d = datas_head;
+        datas_head = d->next;
+        free(d);
+    }
+    nodes_tail = NULL;
+    funcs_tail = NULL;
+    datas_tail = NULL;
+    free(update);
+    update = NULL;
+    cycle = 0;
+    tick_ms = DEFAULT_TICK_MS;
+    now_ms = 0;
+    free(deps);
+    deps = NULL;
+    on_change = 0;
+}
+typedef void (*serial_output_t)(uint8_t *p, int len);
+static serial_output_t serial_output;
+static uint8_t frame[5 + MAX_PAYLOAD + 4];
+static int frame_len;
+static int last_seq = -1;
+static uint8_t *upload;
+static int upload_len, upload_expected;
+void emfrp_set_serial_output(serial_output_t f)
+{
+    serial_output = f;
+}
+void send_frame(uint8_t kind, uint8_t seq, uint8_t *payload, int len)
+{
+    uint8_t buf[5 + MAX_PAYLOAD + 4];
+    uint32_t crc;
+    if (serial_output == NULL)
+        return;
+    buf[0] = SYNC;
+    buf[1] = kind;
+    buf[2] = seq;
+    buf[3] = len & 0xff;
+    buf[4] = len >> 8;
+    memcpy(buf + 5, payload, len);
+    crc = crc32(buf + 1, 4 + len);
+    for (int i = 0; i < 4; ++i)
+    {
+        buf[5 + len + i] = (crc >> (8 * i)) & 0xff;
+    }
+    serial_output(buf, 9 + len);
 }
+// called by the uart driver for every received byte
+void emfrp_serial_input(uint8_t b)
+{
+    uint8_t *p;
+    uint8_t seq, res;
+    int len;
+    if (frame_len == 0 && b != SYNC)
+        return;
+    frame[frame_len++] = b;
+    if (frame_len < 5)
+        return;
+    len = frame[3] | (frame[4] << 8);
+    if (len > MAX_PAYLOAD)
+    { // not a header after all
+        frame_len = 0;
+        return;
+    }
+    if (frame_len < 9 + len)
+        return;
+    frame_len = 0;
+    seq = frame[2];
+    p = frame + 5 + len;
+    if ((uint32_t)next_int(&p) != crc32(frame + 1, 4 + len) || frame[1] < FRAME_BEGIN || frame[1] > FRAME_RESET)
+    {
+        send_frame(FRAME_NAK, seq, NULL, 0);
+        return;
+    }
+    send_frame(FRAME_ACK, seq, NULL, 0);
+    if (seq == last_seq) // our ack was lost
+        return;
+    last_seq = seq;
+    switch (frame[1])
+    {
+    case FRAME_BEGIN:
+        p = frame + 5;
+        upload_expected = len == 4 ? next_int(&p) : 0;
+        free(upload);
+        upload = NULL;
+        if (upload_expected > 0 && upload_expected <= MAX_UPLOAD)
+            upload = (uint8_t *)malloc(upload_expected);
+        upload_len = 0;
+        if (upload == NULL)
+        {
+            res = LOAD_BAD_LENGTH;
+            send_frame(FRAME_RESULT, 0, &res, 1);
+        }
+        break;
+    case FRAME_DATA:
+        if (upload == NULL || upload_len + len > upload_expected)
+        { // without a Begin, or more than it announced
+            free(upload);
+            upload = NULL;
+            res = LOAD_BAD_LENGTH;
+            send_frame(FRAME_RESULT, 0, &res, 1);
+            break;
+        }
+        memcpy(upload + upload_len, frame + 5, len);
+        upload_len += len;
+        if (upload_len == upload_expected)
+        {
+            res = emfrp_set_new_code(upload, upload_len);
+            send_frame(FRAME_RESULT, 0, &res, 1);
+            free(upload);
+            upload = NULL;
+        }
+        break;
+    case FRAME_RESET:
+        emfrp_reset();
+        res = LOAD_OK;
+        send_frame(FRAME_RESULT, 0, &res, 1);
+        break;
+    }
+}
+void put_u32(uint8_t *p, uint32_t v)
+{
+    for (int i = 0; i < 4; ++i)
+    {
+        p[i] = (v >> (8 * i)) & 0xff;
+    }
+}
+// called after every update with the time of the board in ms.
+// reports the nodes whose value differs from the last cycle
+void emfrp_send_telemetry(uint32_t now_ms)
+{
+    uint8_t payload[MAX_PAYLOAD];
+    int len = TELEMETRY_HEADER_SIZE, i = 0;
+    ++cycle;
+    put_u32(payload, cycle);
+    put_u32(payload + 4, now_ms);
+    for (node_t *p = nodes_head; p != NULL; p = p->next, ++i)
+    {
+        if (p->i_action.kind == ACTION_NONE || (!telemetry_full && p->v == p->vlast))
+            continue;
+        if (len + TELEMETRY_ENTRY_SIZE > MAX_PAYLOAD)
+        { // the rest goes to another frame of the same cycle
+            send_frame(FRAME_TELEMETRY, 0, payload, len);
+            len = TELEMETRY_HEADER_SIZE;
+        }
+        payload[len] = i;
+        payload[len + 1] = VALUE_INT;
+        put_u32(payload + len + 2, p->v);
+        len += TELEMETRY_ENTRY_SIZE;
+    }
+    telemetry_full = 0;
+    send_frame(FRAME_TELEMETRY, 0, payload, len);
+}
+// one update cycle, to be called by the board every emfrp_tick_ms()
+exec_result_t emfrp_update(void)
+{
+    exec_result_t res;
+    now_ms += tick_ms;
+    res = emfrp_exec(update);
+    if (on_change)
+    {
+        for (node_t *nd = nodes_head; nd != NULL; nd = nd->next)
+            nd->dirty = nd->always;
+    }
+    return res;
+}
+uint32_t emfrp_tick_ms(void)
+{
+    return tick_ms;
+}
+int emfrp_node_count(void)
+{
+    int n = 0;
+    for (node_t *p = nodes_head; p != NULL; p = p->next)
+        ++n;
+    return n;
+}
+// returns 0 if node i was deleted
+int emfrp_node_value(int i, int *v)
+{
+    node_t *p = node_b(i);
+    *v = p->v;
+    return p->i_action.kind != ACTION_NONE;
+}
+#ifndef EMFRP_LIBRARY
 int main(void)
 {
-    uint8_t code[] = {28, 0, 0, 0, 6, 0, 0, 0, 2, 0, 0, 0, 0, 13, 17, 0, 0, 0, 15, 0, 6, 7, 2, 1, 0, 0, 0, 8, 5, 2, 0, 0, 0, 0, 23, 26, 18, 14, 0, 16, 0, 26};
-    emfrp_set_new_code(code);
+    uint8_t code[] = {69, 77, 70, 82, 2, 2, 6, 0, 118, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 88, 0, 0, 0, 20, 0, 0, 0, 2, 0, 0, 0, 108, 0, 0, 0, 6, 0, 0, 0, 3, 0, 0, 0, 114, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 114, 0, 0, 0, 0, 0, 0, 0, 5, 0, 0, 0, 114, 0, 0, 0, 0, 0, 0, 0, 6, 0, 0, 0, 114, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 13, 9, 0, 0, 0, 17, 0, 2, 1, 0, 0, 0, 4, 23, 26, 18, 14, 0, 16, 0, 26, 51, 234, 182, 133};
+    if (emfrp_set_new_code(code, sizeof(code)) != LOAD_OK)
+    {
+        printf("INVALID CODE\n");
+        return 1;
+    }
     for (int i = 0; i < 10; i++)
     {
-        if (emfrp_exec(update) == OK)
+        if (emfrp_update() == OK)
         {
+            emfrp_send_telemetry(now_ms);
 #ifdef DEBUG
             printf("\n\n");
             print_node("node info");
@@ -357,3 +1106,4 @@ int main(void)
         }
     }
 }
+#endif
@@ -0,0 +1,370 @@
+use crate::container::*;
+use crate::insn::*;
+
+// a textual form of the bytecode for hand-written VM tests
+//
+//   .init
+//     nil
+//     allocnodenew {
+//       bool true
+//       je L1          ; je/j pick the 8 or 32 bit form, je8/j8/je32/j32 force one
+//       int 1
+//       j L2
+//     L1:
+//       int 2
+//     L2:
+//       return
+//     }
+//     halt
+//   .update
+//     savelast
+//     updatenode 0
+//     setnode 0
+//     halt
+//
+// labels are local to the enclosing `{ ... }`, since jump offsets are relative
+// to the node or function body
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmErr {
+    pub line: usize, // 1-based
+    pub msg: String,
+}
+impl std::fmt::Display for AsmErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.msg)
+    }
+}
+type AResult<T> = Result<T, AsmErr>;
+
+// assembles a whole program (`.init` and `.update` sections) to what Image::encode
+// produces; the function and data tables are left empty
+pub fn assemble(src: &str) -> AResult<Vec<u8>> {
+    let mut init = vec![];
+    let mut upd = vec![];
+    let mut section = None;
+    for (line, toks) in lines(src) {
+        match toks[..] {
+            [".init"] => section = Some(&mut init),
+            [".update"] => section = Some(&mut upd),
+            _ => match &mut section {
+                Some(s) => s.push((line, toks)),
+                None => return err(line, "expected `.init` or `.update`"),
+            },
+        }
+    }
+    let image = Image {
+        init: block(&mut init.into_iter(), false)?,
+        update: block(&mut upd.into_iter(), false)?,
+        ..Default::default()
+    };
+    Ok(image.encode())
+}
+
+// assembles a list of instructions without sections
+pub fn assemble_insns(src: &str) -> AResult<Vec<Insn>> {
+    block(&mut lines(src), false)
+}
+
+fn lines(src: &str) -> impl Iterator<Item = (usize, Vec<&str>)> {
+    src.lines().enumerate().filter_map(|(i, l)| {
+        let l = l.split(';').next().unwrap();
+        let toks: Vec<&str> = l.split_whitespace().collect();
+        (!toks.is_empty()).then_some((i + 1, toks))
+    })
+}
+
+enum Item {
+    Insn(Insn),
+    Label(String),
+    // jump to a label; resolve decides whether it needs the 32 bit form
+    Jump {
+        cond: bool,
+        wide: bool,
+        fixed: bool,
+        label: String,
+        line: usize,
+    },
+}
+
+fn block<'a>(
+    lines: &mut impl Iterator<Item = (usize, Vec<&'a str>)>,
+    nested: bool,
+) -> AResult<Vec<Insn>> {
+    let mut items = vec![];
+    let mut closed = false;
+    let mut last_line = 0;
+    while let Some((line, toks)) = lines.next() {
+        last_line = line;
+        match toks[..] {
+            ["}"] if nested => {
+                closed = true;
+                break;
+            }
+            [label] if label.ends_with(':') => {
+                let name = &label[..label.len() - 1];
+                if items
+                    .iter()
+                    .any(|i| matches!(i, Item::Label(l) if l == name))
+                {
+                    return err(line, format!("label `{name}` is defined twice"));
+                }
+                items.push(Item::Label(name.to_string()))
+            }
+            [op, ref args @ ..] => items.push(insn(op, args, line, lines)?),
+            [] => unreachable!(),
+        }
+    }
+    if nested && !closed {
+        return err(last_line, "missing `}`");
+    }
+    resolve(items)
+}
+
+fn insn<'a>(
+    op: &str,
+    args: &[&str],
+    line: usize,
+    lines: &mut impl Iterator<Item = (usize, Vec<&'a str>)>,
+) -> AResult<Item> {
+    let nargs = match op {
+        "allocnode" | "allocfunc" => 2,
+        "allocnodenew" | "allocfuncnew" => 1,
+        "none" | "nil" | "add" | "mul" | "halt" | "allocdatanew" | "return" | "savelast"
+        | "exit" => 0,
+        _ => 1,
+    };
+    if args.len() != nargs {
+        return err(line, format!("`{op}` takes {nargs} operand(s)"));
+    }
+    if matches!(
+        op,
+        "allocnode" | "allocfunc" | "allocnodenew" | "allocfuncnew"
+    ) && args[nargs - 1] != "{"
+    {
+        return err(line, format!("expected `{{` after `{op}`"));
+    }
+    let idx = || index(args[0], line);
+    let insn = match op {
+        "none" => Insn::None,
+        "nil" => Insn::Nil,
+        "int" => Insn::Int(
+            args[0]
+                .parse()
+                .or_else(|_| err(line, format!("invalid integer `{}`", args[0])))?,
+        ),
+        "bool" => match args[0] {
+            "true" => Insn::Bool(true),
+            "false" => Insn::Bool(false),
+            a => return err(line, format!("invalid bool `{a}`")),
+        },
+        "add" => Insn::Add,
+        "mul" => Insn::Mul,
+        "j" | "j8" | "j32" | "je" | "je8" | "je32" => return jump(op, args[0], line),
+        "getlocal" => Insn::GetLocal(idx()?),
+        "setlocal" => Insn::SetLocal(idx()?),
+        "halt" => Insn::Halt,
+        "allocnode" => Insn::AllocNode(idx()?, block(lines, true)?),
+        "allocnodenew" => Insn::AllocNodeNew(block(lines, true)?),
+        "allocfunc" => Insn::AllocFunc(idx()?, block(lines, true)?),
+        "allocfuncnew" => Insn::AllocFuncNew(block(lines, true)?),
+        "allocdata" => Insn::AllocData(idx()?),
+        "allocdatanew" => Insn::AllocDataNew,
+        "return" => Insn::Return,
+        "call" => Insn::Call(idx()?),
+        "getfunc" => Insn::GetFunc(idx()?),
+        "getdata" => Insn::GetData(idx()?),
+        "updatenode" => Insn::UpdateNode(idx()?),
+        "getnode" => Insn::GetNode(idx()?),
+        "setnode" => Insn::SetNode(idx()?),
+        "getlast" => Insn::GetLast(idx()?),
+        "savelast" => Insn::SaveLast,
+        "freenode" => Insn::FreeNode(idx()?),
+        "exit" => Insn::Exit,
+        _ => return err(line, format!("unknown instruction `{op}`")),
+    };
+    Ok(Item::Insn(insn))
+}
+
+fn jump(op: &str, arg: &str, line: usize) -> AResult<Item> {
+    let cond = op.starts_with("je");
+    if let Ok(i) = arg.parse::<i32>() {
+        // a raw byte offset
+        let insn = match op {
+            "j" => Insn::j(i),
+            "je" => Insn::je(i),
+            "j32" => Insn::J32(i),
+            "je32" => Insn::Je32(i),
+            _ => match i8::try_from(i) {
+                Ok(i) if cond => Insn::Je8(i),
+                Ok(i) => Insn::J8(i),
+                Err(_) => return err(line, format!("offset {i} does not fit in `{op}`")),
+            },
+        };
+        return Ok(Item::Insn(insn));
+    }
+    Ok(Item::Jump {
+        cond,
+        wide: op.ends_with("32"),
+        fixed: op.ends_with("8") || op.ends_with("32"),
+        label: arg.to_string(),
+        line,
+    })
+}
+
+fn index(arg: &str, line: usize) -> AResult<usize> {
+    // operands are encoded in a single byte
+    match arg.parse::<u8>() {
+        Ok(i) => Ok(i as usize),
+        Err(_) => err(line, format!("invalid operand `{arg}` (expected 0..=255)")),
+    }
+}
+
+// chooses the size of each jump: every jump starts short and is widened while
+// its offset does not fit, which terminates since jumps only grow
+fn resolve(mut items: Vec<Item>) -> AResult<Vec<Insn>> {
+    let label_offsets = |items: &[Item]| {
+        let mut offset = 0;
+        let mut ret = vec![];
+        for item in items {
+            ret.push(offset);
+            offset += match item {
+                Item::Insn(insn) => bytecode_len(std::slice::from_ref(insn)),
+                Item::Label(..) => 0,
+                Item::Jump { wide, .. } => {
+                    if *wide {
+                        5
+                    } else {
+                        2
+                    }
+                }
+            };
+        }
+        ret
+    };
+    let target = |items: &[Item], label: &str| {
+        items
+            .iter()
+            .position(|i| matches!(i, Item::Label(l) if l == label))
+    };
+    loop {
+        let offsets = label_offsets(&items);
+        let mut changed = false;
+        for i in 0..items.len() {
+            let Item::Jump {
+                wide: false,
+                fixed,
+                label,
+                line,
+                ..
+            } = &items[i]
+            else {
+                continue;
+            };
+            let Some(t) = target(&items, label) else {
+                return err(*line, format!("undefined label `{label}`"));
+            };
+            let rel = offsets[t] as i64 - (offsets[i] + 2) as i64;
+            if i8::try_from(rel).is_err() {
+                if *fixed {
+                    return err(*line, format!("`{label}` is out of range of an 8 bit jump"));
+                }
+                let Item::Jump { wide, .. } = &mut items[i] else {
+                    unreachable!()
+                };
+                *wide = true;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    let offsets = label_offsets(&items);
+    let mut ret = vec![];
+    for (i, item) in items.iter().enumerate() {
+        match item {
+            Item::Insn(insn) => ret.push(insn.clone()),
+            Item::Label(..) => (),
+            Item::Jump {
+                cond,
+                wide,
+                label,
+                line,
+                ..
+            } => {
+                let Some(t) = target(&items, label) else {
+                    return err(*line, format!("undefined label `{label}`"));
+                };
+                let len = if *wide { 5 } else { 2 };
+                let rel = (offsets[t] as i64 - (offsets[i] + len) as i64) as i32;
+                ret.push(match (cond, wide) {
+                    (true, false) => Insn::Je8(rel as i8),
+                    (true, true) => Insn::Je32(rel),
+                    (false, false) => Insn::J8(rel as i8),
+                    (false, true) => Insn::J32(rel),
+                });
+            }
+        }
+    }
+    Ok(ret)
+}
+
+fn err<T>(line: usize, msg: impl Into<String>) -> AResult<T> {
+    Err(AsmErr {
+        line,
+        msg: msg.into(),
+    })
+}
+
+#[test]
+fn assemble_test() {
+    let src = "
+.init
+  nil
+  allocnodenew {
+    bool true
+    je L1      ; then
+    int 1
+    j L2
+  L1:
+    int 2
+  L2:
+    return
+  }
+  halt
+.update
+  savelast
+  updatenode 0
+  setnode 0
+  halt
+";
+    let body = vec![
+        Insn::Bool(true),
+        Insn::Je8(7),
+        Insn::Int(1),
+        Insn::J8(5),
+        Insn::Int(2),
+        Insn::Return,
+    ];
+    let init = vec![Insn::Nil, Insn::AllocNodeNew(body), Insn::Halt];
+    let update = vec![
+        Insn::SaveLast,
+        Insn::UpdateNode(0),
+        Insn::SetNode(0),
+        Insn::Halt,
+    ];
+    let image = Image {
+        init,
+        update,
+        ..Default::default()
+    };
+    assert_eq!(assemble(src), Ok(image.encode()));
+
+    // 30 * 5 bytes do not fit in an 8 bit offset
+    let far = format!("je L\n{}L:\nj L\n", "int 0\n".repeat(30));
+    let insns = assemble_insns(&far).unwrap();
+    assert_eq!(insns[0], Insn::Je32(150));
+    assert_eq!(insns[31], Insn::J8(-2));
+    assert!(assemble_insns(&far.replace("je L", "je8 L")).is_err());
+    assert_eq!(assemble_insns("j M").unwrap_err().line, 1);
+}
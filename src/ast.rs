@@ -1,21 +1,33 @@
+use crate::emtypes::Type;
+
 /*
 TOP => (DEF)* | EXP
-DEF => DEFNODE | DEFDATA | DEFFUNC
-DEFNODE => node init[EXP] ID = EXP
+DEF => DEFNODE | DEFDATA | DEFFUNC | TICK
+DEFNODE => node init[EXP] ID = EXP | input ID : TYPE (every PERIOD)? | output init[EXP] ID = EXP
 DEFDATA => data ID = EXP
 DEFFUNC => func ID (PARAMS) = EXP
 PARAMS = (ID [, ID]* )?
 EXP = if EXP then EXP else EXP  | EXP + TERM  | TERM
 TERM = TERM * TERM | FnCall | INTEGER | BOOLEAN | ID
+TICK => tick PERIOD
 FNCALL = ID(ARGS)
 ARGS =  (EXP [, EXP]*)?
 ID = [a-zA-Z][a-zA-Z0-9]*
+PERIOD = [1-9][0-9]*(ms|s)
+
+every literal word of the grammar is a keyword and cannot be an ID:
+node init data func if then else true false last delete input output Int Bool
+tick every
+input, output, Int and Bool became keywords with input and output nodes, and
+tick and every with the program tick, so older programs that use them as
+names, e.g. `node input = 1`, no longer parse
  */
 #[derive(Debug, Clone)]
 pub enum Program {
     Defs(Vec<Def>),
     Def(Def),
     Exp(Exp),
+    Delete(Id), // delete node x
 }
 #[derive(Debug, Clone)]
 pub enum Def {
@@ -23,6 +35,7 @@ pub enum Def {
         name: Id,
         init: Option<Exp>,
         val: Exp,
+        io: Io,
     },
     Data {
         name: Id,
@@ -33,6 +46,20 @@ pub enum Def {
         params: Vec<Id>,
         body: Exp,
     },
+    // `tick 50ms`: the update period of the whole program
+    Tick {
+        span: Span, // of the keyword, for messages
+        period_ms: u32,
+    },
+}
+
+// nodes bound to a device driver, which is found by the name of the node
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Io {
+    #[default]
+    None,
+    Input(Type, Option<u32>), // read from the driver at most every so many ms
+    Output,                   // every new value is written to the driver
 }
 
 #[derive(Debug, Clone)]
@@ -41,6 +68,7 @@ pub enum Exp {
         cond: Box<Exp>,
         then: Box<Exp>,
         els: Box<Exp>,
+        span: Span,
     },
     Add(Box<Exp>, Box<Term>),
     Term(Box<Term>),
@@ -48,14 +76,179 @@ pub enum Exp {
 #[derive(Debug, Clone)]
 pub enum Term {
     Mul(Box<Term>, Box<Term>),
-    Int(i32),
-    FnCall(Box<Id>, Vec<Exp>),
-    Bool(bool),
-    Last(Id),
+    Int(i32, Span),
+    FnCall(Box<Id>, Vec<Exp>, Span),
+    Bool(bool, Span),
+    Last(Id, Span),
     Id(Id),
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+#[derive(Debug, Clone, Default)]
 pub struct Id {
     pub s: String,
+    pub span: Span,
+}
+// byte offsets into the parsed source
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub lo: usize,
+    pub hi: usize,
+}
+
+// identifiers are compared by name only
+impl PartialEq for Id {
+    fn eq(&self, other: &Self) -> bool {
+        self.s == other.s
+    }
+}
+impl Eq for Id {}
+impl std::hash::Hash for Id {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.s.hash(state)
+    }
+}
+impl Span {
+    pub fn new(lo: usize, hi: usize) -> Self {
+        Span { lo, hi }
+    }
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.lo, other.hi)
+    }
+}
+impl Def {
+    // `input x : T` keeps its value until the driver sets it: `node init[0] x = x@last`
+    pub fn input(name: Id, ty: Type, every_ms: Option<u32>) -> Def {
+        let init = match ty {
+            Type::Bool => Term::Bool(false, name.span),
+            _ => Term::Int(0, name.span),
+        };
+        Def::Node {
+            init: Some(Exp::Term(Box::new(init))),
+            val: Exp::Term(Box::new(Term::Last(name.clone(), name.span))),
+            name,
+            io: Io::Input(ty, every_ms),
+        }
+    }
+    // a tick has no name
+    pub fn name(&self) -> Option<&Id> {
+        match self {
+            Def::Node { name, .. } | Def::Data { name, .. } | Def::Func { name, .. } => Some(name),
+            Def::Tick { .. } => None,
+        }
+    }
+}
+impl Exp {
+    pub fn span(&self) -> Span {
+        match self {
+            Exp::If { span, .. } => *span,
+            Exp::Add(e, t) => e.span().to(t.span()),
+            Exp::Term(t) => t.span(),
+        }
+    }
+}
+impl Exp {
+    // the identifiers read and the functions called, in order of appearance
+    pub fn names<'a>(&'a self, ids: &mut Vec<&'a Id>, calls: &mut Vec<&'a Id>) {
+        match self {
+            Exp::If {
+                cond, then, els, ..
+            } => {
+                cond.names(ids, calls);
+                then.names(ids, calls);
+                els.names(ids, calls);
+            }
+            Exp::Add(e, t) => {
+                e.names(ids, calls);
+                t.names(ids, calls);
+            }
+            Exp::Term(t) => t.names(ids, calls),
+        }
+    }
+}
+impl Term {
+    pub fn names<'a>(&'a self, ids: &mut Vec<&'a Id>, calls: &mut Vec<&'a Id>) {
+        match self {
+            Term::Mul(t1, t2) => {
+                t1.names(ids, calls);
+                t2.names(ids, calls);
+            }
+            Term::FnCall(f, args, _) => {
+                calls.push(f);
+                for arg in args {
+                    arg.names(ids, calls);
+                }
+            }
+            Term::Id(id) => ids.push(id),
+            Term::Int(..) | Term::Bool(..) | Term::Last(..) => (),
+        }
+    }
+    pub fn span(&self) -> Span {
+        match self {
+            Term::Mul(t1, t2) => t1.span().to(t2.span()),
+            Term::Int(_, span)
+            | Term::FnCall(_, _, span)
+            | Term::Bool(_, span)
+            | Term::Last(_, span) => *span,
+            Term::Id(id) => id.span,
+        }
+    }
+}
+// source-like form for the REPL; parentheses are not part of the syntax
+impl std::fmt::Display for Def {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Def::Node {
+                name,
+                io: Io::Input(ty, every_ms),
+                ..
+            } => match every_ms {
+                Some(ms) => write!(f, "input {} : {ty} every {ms}ms", name.s),
+                None => write!(f, "input {} : {ty}", name.s),
+            },
+            Def::Node {
+                name,
+                init,
+                val,
+                io,
+            } => {
+                let kind = if *io == Io::Output { "output" } else { "node" };
+                match init {
+                    Some(init) => write!(f, "{kind} init[{init}] {} = {val}", name.s),
+                    None => write!(f, "{kind} {} = {val}", name.s),
+                }
+            }
+            Def::Data { name, val } => write!(f, "data {} = {val}", name.s),
+            Def::Func { name, params, body } => {
+                let params: Vec<&str> = params.iter().map(|p| p.s.as_str()).collect();
+                write!(f, "func {}({}) = {body}", name.s, params.join(", "))
+            }
+            Def::Tick { period_ms, .. } => write!(f, "tick {period_ms}ms"),
+        }
+    }
+}
+impl std::fmt::Display for Exp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Exp::If {
+                cond, then, els, ..
+            } => write!(f, "if {cond} then {then} else {els}"),
+            Exp::Add(e, t) => write!(f, "{e} + {t}"),
+            Exp::Term(t) => write!(f, "{t}"),
+        }
+    }
+}
+impl std::fmt::Display for Term {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Term::Mul(t1, t2) => write!(f, "{t1} * {t2}"),
+            Term::Int(i, _) => write!(f, "{i}"),
+            Term::FnCall(name, args, _) => {
+                let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+                write!(f, "{}({})", name.s, args.join(", "))
+            }
+            Term::Bool(b, _) => write!(f, "{b}"),
+            Term::Last(id, _) => write!(f, "{}@last", id.s),
+            Term::Id(id) => write!(f, "{}", id.s),
+        }
+    }
 }
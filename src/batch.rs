@@ -0,0 +1,103 @@
+use std::time::Duration;
+
+use serial2::SerialPort;
+
+use crate::link::*;
+use crate::machine::*;
+use crate::repl::{Session, Target};
+use crate::telemetry::LiveValues;
+
+// how long `run` waits for the device to report a cycle
+const CYCLE_TIMEOUT: Duration = Duration::from_secs(5);
+
+fn build(path: &str, on_change: bool) -> Result<(Session, Vec<u8>), String> {
+    let src = std::fs::read_to_string(path).map_err(|e| format!("cannot read {path}: {e}\n"))?;
+    let mut session = Session::new(Target::None);
+    session.set_on_change(on_change);
+    let code = session.compile(path, &src)?;
+    Ok((session, code))
+}
+
+// `compile prog.emfrp -o prog.bin`: writes what would be uploaded
+pub fn compile(path: &str, out: &str, on_change: bool) -> Result<(), String> {
+    let (_, code) = build(path, on_change)?;
+    std::fs::write(out, code).map_err(|e| format!("cannot write {out}: {e}\n"))
+}
+
+// `run prog.emfrp --cycles n`: prints the node values after every update cycle.
+// without a link the program runs on the machine of this process
+pub fn run(
+    path: &str,
+    cycles: u32,
+    link: Option<Link<SerialPort>>,
+    on_change: bool,
+) -> Result<(), String> {
+    let (session, code) = build(path, on_change)?;
+    let names = session.node_names();
+    let mut live = LiveValues::new();
+    let show = |live: &LiveValues| {
+        if let Some(s) = live.render(&names) {
+            println!("{s}");
+        }
+    };
+    match link {
+        Some(mut link) => {
+            let res = link
+                .upload(&code)
+                .map_err(|e| format!("upload failed: {e}\n"))?;
+            if res.code != 0 {
+                return Err(format!("device: {res}\n"));
+            }
+            println!("device: {res}");
+            for _ in 0..cycles {
+                match link.telemetry(CYCLE_TIMEOUT) {
+                    Ok(Some(sample)) => live.apply(sample),
+                    Ok(None) => return Err("the device stopped reporting cycles\n".to_string()),
+                    Err(e) => return Err(format!("telemetry: {e}\n")),
+                }
+                show(&live);
+            }
+        }
+        None => {
+            let (mut machine, msgs) = Machine::new();
+            machine.new_code(Code::Image(code));
+            let msg = msgs.recv().unwrap_or_default();
+            if load_failed(&msg) {
+                return Err(format!("machine: {msg}\n"));
+            }
+            println!("{msg}");
+            for _ in 0..cycles {
+                machine
+                    .step(1)
+                    .map_err(|e| format!("update error: {e}\n"))?;
+                live.apply(machine.telemetry());
+                show(&live);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn compile_file_test() {
+    use crate::container::Image;
+    let dir = std::env::temp_dir().join(format!("emfrp-batch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let src = dir.join("prog.emfrp");
+    let out = dir.join("prog.bin");
+    // several definitions over several lines
+    std::fs::write(
+        &src,
+        "func double(x) = x * 2\nnode init[0] a = a@last + 1\nnode b = double(a)\n",
+    )
+    .unwrap();
+    compile(src.to_str().unwrap(), out.to_str().unwrap(), false).unwrap();
+    let image = Image::decode(&std::fs::read(&out).unwrap()).unwrap();
+    assert_eq!(image.funcs[0].name, "double");
+    assert!(!image.update.is_empty());
+
+    std::fs::write(&src, "node a = b").unwrap();
+    let e = compile(src.to_str().unwrap(), out.to_str().unwrap(), false).unwrap_err();
+    assert!(e.contains("prog.emfrp:1:"), "{e}");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
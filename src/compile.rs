@@ -1,5 +1,6 @@
 use std::collections::{HashMap, VecDeque};
 
+use crate::container::{DepEntry, DriverEntry, DriverKind, FuncEntry, Image};
 use crate::datastructure::List;
 use crate::insn::*;
 use crate::{ast::*, DEBUG, MAX_NUMBER_OF_NODE};
@@ -9,27 +10,86 @@ impl RuntimeNodeIndex {
         self.0
     }
 }
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 struct NodeInfo {
     name: Id,
     is_new_name: bool,
-    pointed: List<usize>, //index
+    pointed: List<usize>,      //index
+    last_pointed: List<usize>, // nodes read through @last
+    deleted: bool,             // the index stays reserved so that GetNode offsets remain valid
+    def: String,               // as shown by the REPL
+    body: Vec<Insn>,
+    io: Io,
+}
+// what the REPL shows about a node
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NodeView<'a> {
+    pub index: usize,
+    pub def: &'a str,
+    pub body: &'a [Insn],
+    pub deps: Vec<&'a str>,
+    pub last_deps: Vec<&'a str>,
+    pub users: Vec<&'a str>, // nodes that depend on this one
+}
+#[derive(Debug, Clone)]
+struct FuncInfo {
+    name: Id,
+    is_new_name: bool,
+    nparams: usize,
+    calls: Vec<Id>, // the functions its body calls
+}
+#[derive(Debug, Clone)]
+struct DataInfo {
+    name: Id,
+    is_new_name: bool,
 }
 
 enum SortResult {
     Success(Vec<usize>),
-    CicularRef,
+    CicularRef(Vec<usize>), // a -> b -> a
 }
+#[derive(Clone)]
 pub struct Compiler {
     codes: Vec<Insn>,
     node_info: Vec<NodeInfo>,
+    func_info: Vec<FuncInfo>,
+    data_info: Vec<DataInfo>,
     symbol_table: Vec<Id>,
+    in_func: bool,
+    tick_ms: u32,    // set by `tick`, kept for later uploads
+    on_change: bool, // emit dependencies so that runtimes update only what changed
 }
 
 #[derive(Debug)]
 pub enum CompileErr<'a> {
     IdNotFound(&'a Id),
-    CircularRef,
+    LastOfNonNode(&'a Id),
+    ArityMismatch {
+        name: &'a Id,
+        expected: usize,
+        found: usize,
+    },
+    DuplicateDef(&'a Id),
+    DuplicateTick(Span), // the second one
+    NodeInUse {
+        name: &'a Id,
+        user: Id,
+    },
+    // at is the definition in the compiled program that takes part in the cycle
+    CircularRef {
+        cycle: Vec<Id>,
+        at: &'a Id,
+    },
+    // datas of a program that read each other, directly or through functions
+    CircularData {
+        cycle: Vec<Id>,
+        at: &'a Id,
+    },
+    // at is the call in the compiled program that starts the cycle
+    RecursiveFunc {
+        cycle: Vec<Id>,
+        at: &'a Id,
+    },
     TooManyNodes,
 }
 pub enum CompiledCode {
@@ -38,39 +98,77 @@ pub enum CompiledCode {
 }
 type CResult<'a, T> = Result<T, CompileErr<'a>>;
 
+impl Default for Compiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl Compiler {
     pub fn compile<'a, 'b: 'a>(
         &'b mut self,
         prog: &'a Program,
     ) -> Result<CompiledCode, CompileErr<'a>> {
-        assert!(self.codes.len() == 0);
+        assert!(self.codes.is_empty());
+        // registering mutates node_info (including pointed lists of redefined nodes)
+        // before errors such as a circular reference can be found, so compile works on
+        // the live state and puts the snapshot back if anything fails
+        let snapshot = (
+            self.node_info.clone(),
+            self.func_info.clone(),
+            self.data_info.clone(),
+            self.tick_ms,
+        );
+        let res = self.compile_prog(prog);
+        if res.is_err() {
+            (self.node_info, self.func_info, self.data_info, self.tick_ms) = snapshot;
+            self.codes.clear();
+            self.symbol_table.clear();
+            self.in_func = false;
+        }
+        res
+    }
+    fn compile_prog<'a>(&mut self, prog: &'a Program) -> CResult<'a, CompiledCode> {
         if let Program::Exp(e) = prog {
             e.emit_code(self)?;
             let mut e = self.insn_popall();
             e.push(Insn::Exit);
             return Ok(CompiledCode::Exp(e));
         }
-
-        self.register_new_node(prog)?;
-        if self.node_info.len() > MAX_NUMBER_OF_NODE {
-            return Err(CompileErr::TooManyNodes);
+        if let Program::Delete(name) = prog {
+            let i = self.delete_node(name)?;
+            self.push_insn(Insn::FreeNode(i));
+        } else {
+            self.register_new_node(prog)?;
+            if self.node_info.len() > MAX_NUMBER_OF_NODE {
+                return Err(CompileErr::TooManyNodes);
+            }
+            self.emit_alloc_node(prog)?;
         }
-        self.emit_alloc_node(prog)?;
         self.push_insn(Insn::Halt);
         let init = self.insn_popall();
 
         let sorted_nodes = match self.topological_sort() {
             SortResult::Success(nd) => nd,
-            SortResult::CicularRef => return Err(CompileErr::CircularRef),
-        };
-        if DEBUG {
-            print!("[dependency]");
-            for i in &sorted_nodes {
-                print!(" -> {}", self.node_info[*i].name.s);
+            SortResult::CicularRef(cycle) => {
+                let cycle: Vec<Id> = cycle
+                    .into_iter()
+                    .map(|i| self.node_info[i].name.clone())
+                    .collect();
+                let at = match prog {
+                    Program::Defs(defs) => defs
+                        .iter()
+                        .find_map(|def| match def {
+                            Def::Node { name, .. } if cycle.contains(name) => Some(name),
+                            _ => None,
+                        })
+                        .unwrap(),
+                    Program::Def(Def::Node { name, .. }) => name,
+                    _ => unreachable!(),
+                };
+                return Err(CompileErr::CircularRef { cycle, at });
             }
-            println!("")
-        }
-
+        };
         let mut upd = Vec::with_capacity(2 * sorted_nodes.len() + 2);
         upd.push(Insn::SaveLast);
         for id in sorted_nodes {
@@ -84,45 +182,191 @@ impl Compiler {
     fn register_new_node<'a>(&mut self, prog: &'a Program) -> CResult<'a, ()> {
         match prog {
             Program::Defs(defs) => {
-                for def in defs {
+                for (i, def) in defs.iter().enumerate() {
+                    let same_kind = std::mem::discriminant(def);
+                    if !defs[..i]
+                        .iter()
+                        .any(|d| std::mem::discriminant(d) == same_kind && d.name() == def.name())
+                    {
+                        continue;
+                    }
+                    return Err(match def {
+                        Def::Tick { span, .. } => CompileErr::DuplicateTick(*span),
+                        _ => CompileErr::DuplicateDef(def.name().unwrap()),
+                    });
+                }
+                self.check_recursion(defs)?;
+                // AllocDataNew takes the slots in the order datas are evaluated
+                let datas = data_order(defs)?;
+                let others = defs.iter().filter(|def| !matches!(def, Def::Data { .. }));
+                for def in others.chain(datas) {
                     match self.register_new_node_one(def) {
                         Ok(()) => continue,
                         Err(e) => return Err(e),
                     }
                 }
+                // nodes defined later in the block are only visible once every name is registered
+                for def in defs {
+                    if let Def::Node { name, val, .. } = def {
+                        let i = self.node_offset(name).unwrap();
+                        (self.node_info[i].pointed, self.node_info[i].last_pointed) =
+                            self.dependency(val);
+                    }
+                }
                 Ok(())
             }
-            Program::Def(def) => self.register_new_node_one(def),
-            Program::Exp(_) => Ok(()),
+            Program::Def(def) => {
+                // a data that reads itself
+                self.check_recursion(std::slice::from_ref(def))?;
+                data_order(std::slice::from_ref(def))?;
+                self.register_new_node_one(def)
+            }
+            Program::Exp(_) | Program::Delete(_) => Ok(()),
+        }
+    }
+
+    // the runtimes have no room for recursion: a function must not call itself,
+    // directly or through others, including those of earlier uploads
+    fn check_recursion<'a>(&self, defs: &'a [Def]) -> CResult<'a, ()> {
+        let block: Vec<(&Id, Vec<&'a Id>)> = defs
+            .iter()
+            .filter_map(|def| match def {
+                Def::Func { name, body, .. } => Some((name, called(body))),
+                _ => None,
+            })
+            .collect();
+        let calls = |f: &Id| -> Vec<Id> {
+            match block.iter().find(|(g, _)| *g == f) {
+                Some((_, calls)) => calls.iter().map(|id| (*id).clone()).collect(),
+                None => match self.func_offset(f) {
+                    Some(i) => self.func_info[i].calls.clone(),
+                    None => vec![],
+                },
+            }
+        };
+        // the functions from `from` to `to`, both included
+        fn path(
+            from: &Id,
+            to: &Id,
+            calls: &dyn Fn(&Id) -> Vec<Id>,
+            seen: &mut Vec<Id>,
+        ) -> Option<Vec<Id>> {
+            if from == to {
+                return Some(vec![from.clone()]);
+            }
+            if seen.contains(from) {
+                return None;
+            }
+            seen.push(from.clone());
+            for g in calls(from) {
+                if let Some(mut p) = path(&g, to, calls, seen) {
+                    p.insert(0, from.clone());
+                    return Some(p);
+                }
+            }
+            None
         }
+        // every cycle goes through a function of the block, since there was
+        // none before it
+        for (f, sites) in &block {
+            for at in sites {
+                if let Some(p) = path(at, f, &calls, &mut vec![]) {
+                    let mut cycle = vec![(*f).clone()];
+                    cycle.extend(p);
+                    return Err(CompileErr::RecursiveFunc { cycle, at });
+                }
+            }
+        }
+        Ok(())
     }
 
-    fn unregister_node(&mut self, _dep: List<usize>) {
-        // do nothing
+    // the index stays reserved as a tombstone; the node no longer reads others
+    fn unregister_node(&mut self, i: usize) {
+        let nd = &mut self.node_info[i];
+        nd.deleted = true;
+        nd.pointed = List::new();
+        nd.last_pointed = List::new();
+    }
+    // the node must not be used by any other node, including through @last
+    fn delete_node<'a>(&mut self, name: &'a Id) -> CResult<'a, usize> {
+        let Some(i) = self.node_offset(name) else {
+            return Err(CompileErr::IdNotFound(name));
+        };
+        for (j, nd) in self.node_info.iter().enumerate() {
+            if j != i && !nd.deleted && (nd.pointed.contains(&i) || nd.last_pointed.contains(&i)) {
+                return Err(CompileErr::NodeInUse {
+                    name,
+                    user: nd.name.clone(),
+                });
+            }
+        }
+        self.unregister_node(i);
+        Ok(i)
+    }
+    fn dependency(&self, val: &Exp) -> (List<usize>, List<usize>) {
+        let mut pointed = List::new();
+        let mut last_pointed = List::new();
+        val.to_dependency(&mut pointed, false, self);
+        val.to_dependency(&mut last_pointed, true, self);
+        (pointed, last_pointed)
     }
     fn register_new_node_one<'a>(&mut self, def: &'a Def) -> CResult<'a, ()> {
         match def {
-            Def::Node { name, init: _, val } => match self.node_offset(name) {
+            Def::Node { name, val, io, .. } => match self.node_offset(name) {
                 Some(i) => {
                     // node of the same name exist
-                    let mut pointed = List::new();
-                    val.to_dependency(&mut pointed, self);
-                    std::mem::swap(&mut pointed, &mut self.node_info[i].pointed);
-                    self.unregister_node(pointed);
+                    let (pointed, last_pointed) = self.dependency(val);
+                    self.node_info[i].is_new_name = false;
+                    self.node_info[i].io = io.clone();
+                    self.node_info[i].pointed = pointed;
+                    self.node_info[i].last_pointed = last_pointed;
                     Ok(())
                 }
                 None => {
-                    let mut pointed = List::new();
-                    val.to_dependency(&mut pointed, self);
+                    let (pointed, last_pointed) = self.dependency(val);
                     self.node_info.push(NodeInfo {
                         name: name.clone(),
                         is_new_name: true,
                         pointed,
+                        last_pointed,
+                        deleted: false,
+                        io: io.clone(),
+                        ..Default::default()
                     });
                     Ok(())
                 }
             },
-            _ => Ok(()),
+            Def::Func { name, params, body } => {
+                let calls = called(body).into_iter().cloned().collect();
+                match self.func_offset(name) {
+                    Some(i) => {
+                        self.func_info[i].is_new_name = false;
+                        self.func_info[i].nparams = params.len();
+                        self.func_info[i].calls = calls;
+                    }
+                    None => self.func_info.push(FuncInfo {
+                        name: name.clone(),
+                        is_new_name: true,
+                        nparams: params.len(),
+                        calls,
+                    }),
+                }
+                Ok(())
+            }
+            Def::Data { name, .. } => {
+                match self.data_offset(name) {
+                    Some(i) => self.data_info[i].is_new_name = false,
+                    None => self.data_info.push(DataInfo {
+                        name: name.clone(),
+                        is_new_name: true,
+                    }),
+                }
+                Ok(())
+            }
+            Def::Tick { period_ms, .. } => {
+                self.tick_ms = *period_ms;
+                Ok(())
+            }
         }
     }
     fn push_insn(&mut self, insn: Insn) {
@@ -135,12 +379,141 @@ impl Compiler {
         std::mem::swap(&mut self.codes, &mut ret);
         Ok(ret)
     }
+    // parameters are resolved to GetLocal and nodes are not visible from function bodies
+    fn compile_func_body<'a>(&mut self, params: &[Id], body: &'a Exp) -> CResult<'a, Vec<Insn>> {
+        let symbol_table = std::mem::replace(&mut self.symbol_table, params.to_vec());
+        self.in_func = true;
+        let ret = self.compile_exp(body);
+        self.in_func = false;
+        self.symbol_table = symbol_table;
+        ret
+    }
 
     pub fn new() -> Self {
         Compiler {
             codes: vec![],
             node_info: vec![],
+            func_info: vec![],
+            data_info: vec![],
             symbol_table: vec![],
+            in_func: false,
+            tick_ms: 0,
+            on_change: false,
+        }
+    }
+    pub fn set_on_change(&mut self, on_change: bool) {
+        self.on_change = on_change;
+    }
+    // wraps compiled code for upload, with the names of every function and data
+    pub fn image(&self, init: Vec<Insn>, update: Vec<Insn>) -> Image {
+        Image {
+            tick_ms: self.tick_ms,
+            init,
+            update,
+            funcs: self
+                .func_info
+                .iter()
+                .map(|f| FuncEntry {
+                    name: f.name.s.clone(),
+                    nparams: f.nparams,
+                })
+                .collect(),
+            datas: self.data_info.iter().map(|d| d.name.s.clone()).collect(),
+            drivers: self
+                .node_info
+                .iter()
+                .enumerate()
+                .filter(|(_, n)| !n.deleted)
+                .filter_map(|(i, n)| {
+                    let (kind, every_ms) = match n.io {
+                        Io::None => return None,
+                        Io::Input(_, every_ms) => (DriverKind::Input, every_ms.unwrap_or(0)),
+                        Io::Output => (DriverKind::Output, 0),
+                    };
+                    Some(DriverEntry {
+                        node: i,
+                        kind,
+                        name: n.name.s.clone(),
+                        every_ms,
+                    })
+                })
+                .collect(),
+            deps: if self.on_change { self.deps() } else { vec![] },
+        }
+    }
+    // for every live node, the nodes that read it. nodes that read a driver or
+    // a value @last are updated every cycle, the others only when one of the
+    // nodes they read has changed
+    fn deps(&self) -> Vec<DepEntry> {
+        let live = || {
+            self.node_info
+                .iter()
+                .enumerate()
+                .filter(|(_, n)| !n.deleted)
+        };
+        live()
+            .map(|(i, n)| DepEntry {
+                node: i,
+                always: !n.last_pointed.is_empty() || matches!(n.io, Io::Input(..)),
+                users: live()
+                    .filter(|(_, m)| m.pointed.contains(&i))
+                    .map(|(j, _)| j)
+                    .collect(),
+            })
+            .collect()
+    }
+    // names by node index, None for deleted nodes; used to decode telemetry
+    pub fn node_names(&self) -> Vec<Option<String>> {
+        self.node_info
+            .iter()
+            .map(|n| (!n.deleted).then(|| n.name.s.clone()))
+            .collect()
+    }
+    pub fn node(&self, name: &str) -> Option<NodeView<'_>> {
+        let i = self
+            .node_info
+            .iter()
+            .position(|n| n.name.s == name && !n.deleted)?;
+        let names = |l: &List<usize>| {
+            let mut v: Vec<&str> = l
+                .iter()
+                .map(|j| self.node_info[*j].name.s.as_str())
+                .collect();
+            v.reverse(); // List pushes to the front
+            v
+        };
+        let n = &self.node_info[i];
+        Some(NodeView {
+            index: i,
+            def: &n.def,
+            body: &n.body,
+            deps: names(&n.pointed),
+            last_deps: names(&n.last_pointed),
+            users: self
+                .node_info
+                .iter()
+                .filter(|m| !m.deleted && m.pointed.contains(&i))
+                .map(|m| m.name.s.as_str())
+                .collect(),
+        })
+    }
+    // live nodes in order of definition
+    pub fn nodes(&self) -> Vec<NodeView<'_>> {
+        self.node_info
+            .iter()
+            .filter(|n| !n.deleted)
+            .filter_map(|n| self.node(&n.name.s))
+            .collect()
+    }
+    // the order in which the update code evaluates nodes
+    pub fn update_order(&self) -> Vec<&str> {
+        match self.topological_sort() {
+            SortResult::Success(order) => order
+                .into_iter()
+                .map(|i| self.node_info[i].name.s.as_str())
+                .collect(),
+            // compile never keeps a cycle
+            SortResult::CicularRef(_) => unreachable!(),
         }
     }
 
@@ -157,10 +530,14 @@ impl Compiler {
             NodeInfo {
                 name,
                 pointed,
-                is_new_name: _,
+                deleted,
+                ..
             },
         ) in self.node_info.iter().enumerate()
         {
+            if *deleted {
+                continue;
+            }
             if DEBUG {
                 assert_eq!(self.node_offset(name), Some(i));
             }
@@ -175,15 +552,7 @@ impl Compiler {
 
         while let Some(nd) = q.pop_front() {
             // ndがさすノードのカウントを減らす
-            for (
-                i,
-                NodeInfo {
-                    name: _,
-                    pointed,
-                    is_new_name: _,
-                },
-            ) in self.node_info.iter().enumerate()
-            {
+            for (i, NodeInfo { pointed, .. }) in self.node_info.iter().enumerate() {
                 if pointed.contains(&nd) {
                     *cnt.get_mut(&i).unwrap() -= 1;
                     if *cnt.get(&i).unwrap() == 0 {
@@ -196,17 +565,43 @@ impl Compiler {
         if ret.len() == cnt.len() {
             SortResult::Success(ret)
         } else {
-            SortResult::CicularRef
+            SortResult::CicularRef(self.find_cycle(&ret))
+        }
+    }
+    // every node left unsorted points to another unsorted node, so following
+    // those edges from any of them must end up in a cycle
+    fn find_cycle(&self, sorted: &[usize]) -> Vec<usize> {
+        let rest = |i: &usize| !sorted.contains(i) && !self.node_info[*i].deleted;
+        let mut path = vec![(0..self.node_info.len()).find(rest).unwrap()];
+        loop {
+            let last = *path.last().unwrap();
+            let next = *self.node_info[last]
+                .pointed
+                .iter()
+                .find(|i| rest(i))
+                .unwrap();
+            if let Some(pos) = path.iter().position(|i| *i == next) {
+                let mut cycle = path.split_off(pos);
+                cycle.push(next);
+                return cycle;
+            }
+            path.push(next);
         }
     }
 
     fn node_offset(&self, name: &Id) -> Option<usize> {
         for (i, e) in self.node_info.iter().enumerate() {
-            if name == &e.name {
+            if name == &e.name && !e.deleted {
                 return Some(i);
             }
         }
         None
+    }
+    fn func_offset(&self, name: &Id) -> Option<usize> {
+        self.func_info.iter().position(|f| &f.name == name)
+    }
+    fn data_offset(&self, name: &Id) -> Option<usize> {
+        self.data_info.iter().position(|d| &d.name == name)
     } /*
       fn contain_node(&self, name: &Id) -> bool {
           matches!(self.node_offset(name), Some(_))
@@ -214,18 +609,28 @@ impl Compiler {
     fn emit_alloc_node<'a>(&mut self, prog: &'a Program) -> CResult<'a, ()> {
         match prog {
             Program::Defs(defs) => {
-                for def in defs {
+                // functions are allocated first and then datas, so that datas and
+                // initial values can use them
+                let (funcs, others): (Vec<&Def>, Vec<&Def>) =
+                    defs.iter().partition(|def| matches!(def, Def::Func { .. }));
+                let datas = data_order(defs)?;
+                let others = others
+                    .into_iter()
+                    .filter(|def| !matches!(def, Def::Data { .. }));
+                for def in funcs.into_iter().chain(datas).chain(others) {
                     self.emit_alloc_node_one(def)?;
                 }
                 Ok(())
             }
             Program::Def(def) => self.emit_alloc_node_one(def),
-            Program::Exp(_) => Ok(()),
+            Program::Exp(_) | Program::Delete(_) => Ok(()),
         }
     }
     fn emit_alloc_node_one<'a>(&mut self, def: &'a Def) -> CResult<'a, ()> {
         match def {
-            Def::Node { name, init, val } => {
+            Def::Node {
+                name, init, val, ..
+            } => {
                 match init {
                     Some(e) => e.emit_code(self)?,
                     None => self.push_insn(Insn::Nil),
@@ -233,6 +638,8 @@ impl Compiler {
                 let mut insn = self.compile_exp(val)?;
                 insn.push(Insn::Return);
                 let offset = self.node_offset(name).unwrap();
+                self.node_info[offset].def = def.to_string();
+                self.node_info[offset].body = insn.clone();
                 let insn = if self.node_info[offset].is_new_name {
                     Insn::AllocNodeNew(insn)
                 } else {
@@ -241,16 +648,126 @@ impl Compiler {
                 self.push_insn(insn);
                 Ok(())
             }
-            Def::Data { .. } => Ok(()),
-            Def::Func { .. } => Ok(()),
+            Def::Data { name, val } => {
+                // data is evaluated once like a function without parameters
+                let insn = self.compile_func_body(&[], val)?;
+                self.codes.extend(insn);
+                let offset = self.data_offset(name).unwrap();
+                let insn = if self.data_info[offset].is_new_name {
+                    Insn::AllocDataNew
+                } else {
+                    Insn::AllocData(offset)
+                };
+                self.push_insn(insn);
+                Ok(())
+            }
+            Def::Func { name, params, body } => {
+                let mut insn = self.compile_func_body(params, body)?;
+                insn.push(Insn::Return);
+                let offset = self.func_offset(name).unwrap();
+                let insn = if self.func_info[offset].is_new_name {
+                    Insn::AllocFuncNew(insn)
+                } else {
+                    Insn::AllocFunc(offset, insn)
+                };
+                self.push_insn(insn);
+                Ok(())
+            }
+            Def::Tick { .. } => Ok(()),
         }
     }
 }
 
+// the datas of a program, each after the datas of the program that it reads
+// directly or through functions of the program
+// the functions that exp calls, in order of appearance
+fn called(exp: &Exp) -> Vec<&Id> {
+    let (mut ids, mut calls) = (vec![], vec![]);
+    exp.names(&mut ids, &mut calls);
+    calls
+}
+
+fn data_order(defs: &[Def]) -> CResult<'_, Vec<&Def>> {
+    let datas: Vec<(&Id, &Def)> = defs
+        .iter()
+        .filter_map(|def| match def {
+            Def::Data { name, .. } => Some((name, def)),
+            _ => None,
+        })
+        .collect();
+    let funcs: HashMap<&Id, (&[Id], &Exp)> = defs
+        .iter()
+        .filter_map(|def| match def {
+            Def::Func { name, params, body } => Some((name, (&params[..], body))),
+            _ => None,
+        })
+        .collect();
+    let reads: Vec<Vec<usize>> = datas
+        .iter()
+        .map(|(_, def)| {
+            let Def::Data { val, .. } = def else {
+                unreachable!()
+            };
+            let (mut ids, mut calls) = (vec![], vec![]);
+            val.names(&mut ids, &mut calls);
+            let mut called = vec![];
+            while let Some(f) = calls.pop() {
+                if called.contains(&f) {
+                    continue;
+                }
+                called.push(f);
+                if let Some((params, body)) = funcs.get(f) {
+                    let mut read = vec![];
+                    body.names(&mut read, &mut calls);
+                    ids.extend(read.into_iter().filter(|id| !params.contains(id)));
+                }
+            }
+            ids.iter()
+                .filter_map(|id| datas.iter().position(|(d, _)| d == id))
+                .collect()
+        })
+        .collect();
+    // depth first, with the datas being visited on the path
+    fn visit(
+        i: usize,
+        reads: &[Vec<usize>],
+        path: &mut Vec<usize>,
+        order: &mut Vec<usize>,
+    ) -> Result<(), Vec<usize>> {
+        if order.contains(&i) {
+            return Ok(());
+        }
+        if let Some(at) = path.iter().position(|j| *j == i) {
+            let mut cycle = path[at..].to_vec();
+            cycle.push(i);
+            return Err(cycle);
+        }
+        path.push(i);
+        for j in &reads[i] {
+            visit(*j, reads, path, order)?;
+        }
+        path.pop();
+        order.push(i);
+        Ok(())
+    }
+    let mut order = vec![];
+    for i in 0..datas.len() {
+        if let Err(cycle) = visit(i, &reads, &mut vec![], &mut order) {
+            return Err(CompileErr::CircularData {
+                cycle: cycle.iter().map(|j| datas[*j].0.clone()).collect(),
+                at: datas[cycle[0]].0,
+            });
+        }
+    }
+    Ok(order.into_iter().map(|i| datas[i].1).collect())
+}
+
 impl Exp {
     pub fn emit_code<'a>(&'a self, c: &mut Compiler) -> CResult<'a, ()> {
         match self {
-            Exp::If { cond, then, els } => {
+            Exp::If {
+                cond, then, els, ..
+            } => {
                 cond.emit_code(c)?;
                 c.push_insn(Insn::Placeholder);
                 let i0 = c.codes.len();
@@ -259,9 +776,9 @@ impl Exp {
                 let i1 = c.codes.len();
                 then.emit_code(c)?;
                 let i2 = c.codes.len();
-                c.codes[i1 as usize - 1] = Insn::j(bytecode_len(&c.codes[i1..i2]) as i32);
+                c.codes[i1 - 1] = Insn::j(bytecode_len(&c.codes[i1..i2]) as i32);
 
-                c.codes[i0 as usize - 1] = Insn::je(bytecode_len(&c.codes[i0..i1]) as i32);
+                c.codes[i0 - 1] = Insn::je(bytecode_len(&c.codes[i0..i1]) as i32);
 
                 Ok(())
             }
@@ -274,18 +791,21 @@ impl Exp {
             Exp::Term(t) => t.emit_code(c),
         }
     }
-    fn to_dependency(&self, lst: &mut List<usize>, cmp: &Compiler) {
+    // collects the nodes read directly, or through @last if last is true
+    fn to_dependency(&self, lst: &mut List<usize>, last: bool, cmp: &Compiler) {
         match self {
-            Exp::If { cond, then, els } => {
-                cond.to_dependency(lst, cmp);
-                then.to_dependency(lst, cmp);
-                els.to_dependency(lst, cmp);
+            Exp::If {
+                cond, then, els, ..
+            } => {
+                cond.to_dependency(lst, last, cmp);
+                then.to_dependency(lst, last, cmp);
+                els.to_dependency(lst, last, cmp);
             }
             Exp::Add(e, t) => {
-                e.to_dependency(lst, cmp);
-                t.to_dependency(lst, cmp);
+                e.to_dependency(lst, last, cmp);
+                t.to_dependency(lst, last, cmp);
             }
-            Exp::Term(t) => t.to_dependency(lst, cmp),
+            Exp::Term(t) => t.to_dependency(lst, last, cmp),
         }
     }
 }
@@ -297,10 +817,30 @@ impl Term {
                 t2.emit_code(c)?;
                 c.push_insn(Insn::Mul);
             }
-            Term::Int(i) => c.push_insn(Insn::Int(*i)),
-            Term::FnCall(_, _) => todo!(),
-            Term::Bool(b) => c.push_insn(Insn::Bool(*b)),
-            Term::Last(id) => c.push_insn(Insn::GetLast(c.node_offset(id).unwrap())),
+            Term::Int(i, _) => c.push_insn(Insn::Int(*i)),
+            Term::FnCall(f, args, _) => {
+                let Some(i) = c.func_offset(f) else {
+                    return Err(CompileErr::IdNotFound(f));
+                };
+                if c.func_info[i].nparams != args.len() {
+                    return Err(CompileErr::ArityMismatch {
+                        name: f,
+                        expected: c.func_info[i].nparams,
+                        found: args.len(),
+                    });
+                }
+                // args are pushed first so that the callee finds them at rbp
+                for arg in args {
+                    arg.emit_code(c)?;
+                }
+                c.push_insn(Insn::GetFunc(i));
+                c.push_insn(Insn::Call(args.len()));
+            }
+            Term::Bool(b, _) => c.push_insn(Insn::Bool(*b)),
+            Term::Last(id, _) => match c.node_offset(id).filter(|_| !c.in_func) {
+                Some(i) => c.push_insn(Insn::GetLast(i)),
+                None => return Err(CompileErr::LastOfNonNode(id)),
+            },
             Term::Id(id) => {
                 for (i, id2) in c.symbol_table.iter().enumerate() {
                     if id == id2 {
@@ -308,36 +848,315 @@ impl Term {
                         return Ok(());
                     }
                 }
-                if let Some(i) = c.node_offset(id) {
+                if let Some(i) = c.node_offset(id).filter(|_| !c.in_func) {
                     c.push_insn(Insn::GetNode(i))
+                } else if let Some(i) = c.data_offset(id) {
+                    c.push_insn(Insn::GetData(i))
                 } else {
-                    todo!()
+                    return Err(CompileErr::IdNotFound(id));
                 }
             }
         }
         Ok(())
     }
-    fn to_dependency(&self, lst: &mut List<usize>, c: &Compiler) {
+    fn to_dependency(&self, lst: &mut List<usize>, last: bool, c: &Compiler) {
         match self {
             Term::Mul(t1, t2) => {
-                t1.to_dependency(lst, c);
-                t2.to_dependency(lst, c);
+                t1.to_dependency(lst, last, c);
+                t2.to_dependency(lst, last, c);
             }
-            Term::Int(_) => return,
-            Term::FnCall(_, args) => {
+            Term::Int(..) => (),
+            Term::FnCall(_, args, _) => {
                 for arg in args {
-                    arg.to_dependency(lst, c);
+                    arg.to_dependency(lst, last, c);
                 }
             }
-            Term::Bool(_) => return,
+            Term::Bool(..) => (),
 
             // node left_variable = (idの式)
-            Term::Id(id) => {
-                if let Some(u) = c.node_offset(id) {
-                    lst.push(u)
-                }
-            }
-            Term::Last(_) => return,
+            Term::Id(id) if !last => match c.node_offset(id) {
+                Some(u) if !lst.contains(&u) => lst.push(u),
+                _ => (),
+            },
+            Term::Last(id, _) if last => match c.node_offset(id) {
+                Some(u) if !lst.contains(&u) => lst.push(u),
+                _ => (),
+            },
+            Term::Id(_) | Term::Last(..) => (),
         }
     }
 }
+#[test]
+fn compile_func_call() {
+    let prog = crate::grammer::ProgramParser::new()
+        .parse("func add(a, b) = a + b node init[0] x = add(x@last, 1)")
+        .unwrap();
+    let mut c = Compiler::new();
+    let Ok(CompiledCode::DefNode { init, .. }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(
+        init,
+        vec![
+            Insn::AllocFuncNew(vec![
+                Insn::GetLocal(0),
+                Insn::GetLocal(1),
+                Insn::Add,
+                Insn::Return
+            ]),
+            Insn::Int(0),
+            Insn::AllocNodeNew(vec![
+                Insn::GetLast(0),
+                Insn::Int(1),
+                Insn::GetFunc(0),
+                Insn::Call(2),
+                Insn::Return
+            ]),
+            Insn::Halt
+        ]
+    );
+}
+#[test]
+fn compile_data_redefinition() {
+    let parser = crate::grammer::ProgramParser::new();
+    let mut c = Compiler::new();
+    let prog = parser.parse("data th = 30 node x = th + 1").unwrap();
+    let Ok(CompiledCode::DefNode { init, .. }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(&init[..2], &[Insn::Int(30), Insn::AllocDataNew]);
+    let prog = parser.parse("data th = 5").unwrap();
+    let Ok(CompiledCode::DefNode { init, .. }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(init, vec![Insn::Int(5), Insn::AllocData(0), Insn::Halt]);
+}
+#[test]
+fn compile_data_order() {
+    let parser = crate::grammer::ProgramParser::new();
+    let mut c = Compiler::new();
+    // b is evaluated first, and takes the first slot
+    let prog = parser
+        .parse("node init[a] x = 1 data a = f() + 1 func f() = b data b = 2")
+        .unwrap();
+    let Ok(CompiledCode::DefNode { init, .. }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(
+        init[1..],
+        [
+            Insn::Int(2),
+            Insn::AllocDataNew,
+            Insn::GetFunc(0),
+            Insn::Call(0),
+            Insn::Int(1),
+            Insn::Add,
+            Insn::AllocDataNew,
+            Insn::GetData(1),
+            Insn::AllocNodeNew(vec![Insn::Int(1), Insn::Return]),
+            Insn::Halt
+        ]
+    );
+    let prog = parser
+        .parse("data p = q func g() = p data q = g()")
+        .unwrap();
+    let Err(CompileErr::CircularData { cycle, at }) = c.compile(&prog) else {
+        panic!()
+    };
+    let cycle: Vec<&str> = cycle.iter().map(|id| id.s.as_str()).collect();
+    assert_eq!(cycle, vec!["p", "q", "p"]);
+    assert_eq!(at.s, "p");
+    let prog = parser.parse("data p = if true then 1 else p").unwrap();
+    assert!(matches!(
+        c.compile(&prog),
+        Err(CompileErr::CircularData { .. })
+    ));
+}
+#[test]
+fn compile_recursion() {
+    let parser = crate::grammer::ProgramParser::new();
+    let mut c = Compiler::new();
+    let prog = parser
+        .parse("func f(b, n) = if b then n else f(true, n + 1)")
+        .unwrap();
+    let Err(CompileErr::RecursiveFunc { cycle, at }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(cycle.len(), 2);
+    assert_eq!(at.s, "f");
+    assert_eq!(at.span, Span { lo: 32, hi: 33 });
+    // through a function of an earlier upload
+    let prog = parser.parse("func g(x) = x func h(x) = g(x) + 1").unwrap();
+    assert!(c.compile(&prog).is_ok());
+    let prog = parser.parse("func g(x) = h(x)").unwrap();
+    let Err(CompileErr::RecursiveFunc { cycle, .. }) = c.compile(&prog) else {
+        panic!()
+    };
+    let cycle: Vec<&str> = cycle.iter().map(|id| id.s.as_str()).collect();
+    assert_eq!(cycle, vec!["g", "h", "g"]);
+}
+#[test]
+fn compile_circular_ref() {
+    let prog = crate::grammer::ProgramParser::new()
+        .parse("node a = b + 1 node b = c node c = a@last + a")
+        .unwrap();
+    let mut c = Compiler::new();
+    let Err(CompileErr::CircularRef { cycle, at }) = c.compile(&prog) else {
+        panic!()
+    };
+    let cycle: Vec<&str> = cycle.iter().map(|id| id.s.as_str()).collect();
+    assert_eq!(cycle, vec!["a", "b", "c", "a"]);
+    assert_eq!(at.s, "a");
+}
+#[test]
+fn compile_err_keeps_session() {
+    let parser = crate::grammer::ProgramParser::new();
+    let mut c = Compiler::new();
+    let prog = parser.parse("node x = 1").unwrap();
+    assert!(c.compile(&prog).is_ok());
+    for src in [
+        "node y = z + 1",
+        "node y = z@last",
+        "func f(a) = x",
+        "func f(a) = a node y = f(1, 2)",
+        "node y = 1 node y = 2",
+        "q * 2",
+    ] {
+        let prog = parser.parse(src).unwrap();
+        assert!(c.compile(&prog).is_err(), "{}", src);
+    }
+    assert_eq!(c.node_info.len(), 1);
+    assert!(c.func_info.is_empty());
+    let prog = parser.parse("node y = x + 1").unwrap();
+    assert!(c.compile(&prog).is_ok());
+}
+#[test]
+fn compile_rejected_cycle_keeps_session() {
+    let parser = crate::grammer::ProgramParser::new();
+    let mut c = Compiler::new();
+    let prog = parser.parse("node a = 1 node b = a + 1").unwrap();
+    assert!(c.compile(&prog).is_ok());
+    // redefining a in terms of b closes a cycle
+    let prog = parser.parse("node a = b").unwrap();
+    assert!(matches!(
+        c.compile(&prog),
+        Err(CompileErr::CircularRef { .. })
+    ));
+    assert!(c.node_info[0].pointed.is_empty());
+    let prog = parser.parse("node c = b + a").unwrap();
+    let Ok(CompiledCode::DefNode { upd, .. }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(
+        upd,
+        vec![
+            Insn::SaveLast,
+            Insn::UpdateNode(0),
+            Insn::SetNode(0),
+            Insn::UpdateNode(1),
+            Insn::SetNode(1),
+            Insn::UpdateNode(2),
+            Insn::SetNode(2),
+            Insn::Halt
+        ]
+    );
+}
+#[test]
+fn compile_delete_node() {
+    let parser = crate::grammer::ProgramParser::new();
+    let mut c = Compiler::new();
+    let prog = parser
+        .parse("node a = 1 node b = a + 1 node d = b@last")
+        .unwrap();
+    assert!(c.compile(&prog).is_ok());
+    for (src, user) in [("delete node a", "b"), ("delete node b", "d")] {
+        let prog = parser.parse(src).unwrap();
+        let Err(CompileErr::NodeInUse { user: u, .. }) = c.compile(&prog) else {
+            panic!("{}", src)
+        };
+        assert_eq!(u.s, user);
+    }
+    let prog = parser.parse("delete node d").unwrap();
+    let Ok(CompiledCode::DefNode { init, upd }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(init, vec![Insn::FreeNode(2), Insn::Halt]);
+    assert!(!upd.contains(&Insn::UpdateNode(2)));
+    let prog = parser.parse("b + d").unwrap();
+    assert!(matches!(c.compile(&prog), Err(CompileErr::IdNotFound(_))));
+    // the slot of a deleted node is not reused
+    let prog = parser.parse("node d = b").unwrap();
+    assert!(c.compile(&prog).is_ok());
+    let d = Id {
+        s: "d".to_string(),
+        ..Default::default()
+    };
+    assert_eq!(c.node_offset(&d), Some(3));
+}
+#[test]
+fn compile_io_nodes() {
+    let parser = crate::grammer::ProgramParser::new();
+    let mut c = Compiler::new();
+    let prog = parser
+        .parse("input temp : Int output led = if btn then temp * 2 else 0 input btn : Bool")
+        .unwrap();
+    let Ok(CompiledCode::DefNode { init, upd }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(c.node("temp").unwrap().def, "input temp : Int");
+    assert_eq!(
+        c.node("btn").unwrap().body,
+        [Insn::GetLast(2), Insn::Return]
+    );
+    let drivers = c.image(init, upd).drivers;
+    let bound: Vec<(usize, DriverKind, &str)> = drivers
+        .iter()
+        .map(|d| (d.node, d.kind, d.name.as_str()))
+        .collect();
+    assert_eq!(
+        bound,
+        [
+            (0, DriverKind::Input, "temp"),
+            (1, DriverKind::Output, "led"),
+            (2, DriverKind::Input, "btn")
+        ]
+    );
+    // an I/O node redefined as a plain node loses its driver
+    let prog = parser.parse("node led = temp").unwrap();
+    let Ok(CompiledCode::DefNode { init, upd }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(c.image(init, upd).drivers.len(), 2);
+    // the new keywords are no longer names
+    assert!(parser.parse("node input = 1").is_err());
+    assert!(parser.parse("data Int = 1").is_err());
+}
+
+#[test]
+fn compile_tick() {
+    let parser = crate::grammer::ProgramParser::new();
+    let mut c = Compiler::new();
+    let prog = parser
+        .parse("tick 50ms input temp : Int every 2s node hot = temp + 1")
+        .unwrap();
+    let Ok(CompiledCode::DefNode { init, upd }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(c.node("temp").unwrap().def, "input temp : Int every 2000ms");
+    let image = c.image(init, upd);
+    assert_eq!(image.tick_ms, 50);
+    assert_eq!(image.drivers[0].every_ms, 2000);
+    // the tick is kept for later uploads
+    let prog = parser.parse("node cold = temp").unwrap();
+    let Ok(CompiledCode::DefNode { init, upd }) = c.compile(&prog) else {
+        panic!()
+    };
+    assert_eq!(c.image(init, upd).tick_ms, 50);
+    assert!(parser.parse("tick 0ms").is_err());
+    assert!(parser.parse("node every = 1").is_err());
+    let prog = parser.parse("tick 10ms tick 20ms").unwrap();
+    assert!(matches!(
+        c.compile(&prog),
+        Err(CompileErr::DuplicateTick(Span { lo: 10, hi: 14 }))
+    ));
+}
@@ -0,0 +1,398 @@
+use crate::insn::*;
+use crate::qstr::*;
+
+// the unit uploaded to the device (all integers are little endian)
+//
+//  0  magic "EMFR"
+//  4  format version: u8
+//  5  opcode set version: u8
+//  6  number of sections: u8
+//  7  reserved: u8
+//  8  total length including the checksum: u32
+// 12  update period in ms, 0 for the default of the runtime: u32
+// 16  section table, 12 bytes per section:
+//       kind: u8, reserved: [u8; 3], offset from the start: u32, length: u32
+//  .. sections
+//  .. CRC32 of everything before it: u32
+//
+// the device needs init, update, the driver bindings with the names they
+// refer to and the dependencies if any; the function and data tables let host
+// tools show names
+pub const MAGIC: [u8; 4] = *b"EMFR";
+pub const FORMAT_VERSION: u8 = 2;
+// bumped whenever an opcode is added or changes meaning. only the same version
+// is accepted, since code for another set would be misread either way. 2 made jump offsets signed, added j32 and je32 and changed
+// what bool and allocnodenew do
+pub const OPCODE_SET_VERSION: u8 = 2;
+const HEADER_SIZE: usize = 16;
+const SECTION_ENTRY_SIZE: usize = 12;
+
+const SECTION_INIT: u8 = 1;
+const SECTION_UPDATE: u8 = 2;
+const SECTION_FUNCS: u8 = 3; // per function: nparams: u8, reserved: u8, name: u16
+const SECTION_DATAS: u8 = 4; // per data: name: u16
+const SECTION_STRINGS: u8 = 5; // NUL terminated names, indexed from 0
+
+// per I/O node: node: u8, kind: u8, name: u16, sampling period in ms or 0: u32.
+// an input with a period is read on the first cycle after the load, then on
+// the first cycle at or after each period, counted from when the last one was
+// due rather than from the reading so that it keeps to the period on average
+// when the period is not a multiple of the tick
+const SECTION_DRIVERS: u8 = 6;
+const DRIVER_ENTRY_SIZE: usize = 8;
+// only with on-change updates; per node: node: u8, always: u8, number of
+// users: u8, users: [u8]. the runtimes refuse the code if any of these is not
+// a node of the program.
+//
+// a program that has this section updates only the nodes whose inputs
+// changed. after a load every node is updated once; after that a node is
+// updated when it is marked always, because it reads a driver or a value
+// @last, or when one of the nodes it reads, which list it as a user, changed.
+// a node that is not updated keeps its value and its output is not written
+const SECTION_DEPS: u8 = 7;
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct Image {
+    pub tick_ms: u32, // 0 leaves the update period to the runtime
+    pub init: Vec<Insn>,
+    pub update: Vec<Insn>,
+    pub funcs: Vec<FuncEntry>, // by function index
+    pub datas: Vec<String>,    // by data index
+    pub drivers: Vec<DriverEntry>,
+    pub deps: Vec<DepEntry>, // empty when every node is updated every cycle
+}
+// a node that the runtime connects to the driver registered under its name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DriverEntry {
+    pub node: usize,
+    pub kind: DriverKind,
+    pub name: String,
+    pub every_ms: u32, // inputs are read at most this often; 0 for every cycle
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DriverKind {
+    Input = 1,
+    Output = 2,
+}
+// which nodes are updated again when a node changes
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DepEntry {
+    pub node: usize,
+    pub always: bool, // reads a driver or a value @last
+    pub users: Vec<usize>,
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FuncEntry {
+    pub name: String,
+    pub nparams: usize,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContainerErr {
+    BadMagic,
+    UnsupportedFormat(u8),
+    UnsupportedOpcodeSet(u8),
+    BadLength(usize), // length found in the header or of the buffer
+    BadChecksum,
+    BadSection(u8),
+    BadString(usize),
+    Decode(DecodeErr),
+}
+impl std::fmt::Display for ContainerErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ContainerErr::BadMagic => write!(f, "not an emfrp program"),
+            ContainerErr::UnsupportedFormat(v) => write!(f, "unsupported format version {v}"),
+            ContainerErr::UnsupportedOpcodeSet(v) => {
+                write!(f, "opcode set version {v}, expected {OPCODE_SET_VERSION}")
+            }
+            ContainerErr::BadLength(len) => write!(f, "invalid length {len}"),
+            ContainerErr::BadChecksum => write!(f, "checksum mismatch"),
+            ContainerErr::BadSection(kind) => write!(f, "invalid section of kind {kind}"),
+            ContainerErr::BadString(i) => write!(f, "no string {i} in the string pool"),
+            ContainerErr::Decode(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Image {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut pool = QstrPool::empty();
+        let mut name = |s: &str| {
+            let i;
+            (pool, i) = std::mem::replace(&mut pool, QstrPool::empty()).insert(s.to_string());
+            (i.0 as u16).to_le_bytes()
+        };
+        let mut funcs = vec![];
+        for f in &self.funcs {
+            funcs.extend([f.nparams as u8, 0]);
+            funcs.extend(name(&f.name));
+        }
+        let mut datas = vec![];
+        for d in &self.datas {
+            datas.extend(name(d));
+        }
+        let mut drivers = vec![];
+        for d in &self.drivers {
+            drivers.extend([d.node as u8, d.kind as u8]);
+            drivers.extend(name(&d.name));
+            drivers.extend(d.every_ms.to_le_bytes());
+        }
+        let mut deps = vec![];
+        for d in &self.deps {
+            deps.extend([d.node as u8, d.always as u8, d.users.len() as u8]);
+            deps.extend(d.users.iter().map(|u| *u as u8));
+        }
+        let mut strings = vec![];
+        for s in pool.strs() {
+            strings.extend(s.as_bytes());
+            strings.push(0);
+        }
+        let sections = [
+            (SECTION_INIT, encode_insns(&self.init)),
+            (SECTION_UPDATE, encode_insns(&self.update)),
+            (SECTION_FUNCS, funcs),
+            (SECTION_DATAS, datas),
+            (SECTION_STRINGS, strings),
+            (SECTION_DRIVERS, drivers),
+            (SECTION_DEPS, deps),
+        ];
+
+        let mut ret = MAGIC.to_vec();
+        ret.extend([FORMAT_VERSION, OPCODE_SET_VERSION, sections.len() as u8, 0]);
+        ret.extend([0; 4]); // total length
+        ret.extend(self.tick_ms.to_le_bytes());
+        let mut offset = HEADER_SIZE + SECTION_ENTRY_SIZE * sections.len();
+        for (kind, bytes) in &sections {
+            ret.extend([*kind, 0, 0, 0]);
+            ret.extend((offset as u32).to_le_bytes());
+            ret.extend((bytes.len() as u32).to_le_bytes());
+            offset += bytes.len();
+        }
+        for (_, bytes) in sections {
+            ret.extend(bytes);
+        }
+        let total = ret.len() + 4;
+        ret[8..12].copy_from_slice(&(total as u32).to_le_bytes());
+        let crc = crc32(&ret);
+        ret.extend(crc.to_le_bytes());
+        ret
+    }
+    // checks the header and checksum the same way emfrp_set_new_code does
+    pub fn decode(bytes: &[u8]) -> Result<Image, ContainerErr> {
+        let sections = sections(bytes)?;
+        let section = |kind: u8| sections[kind as usize].unwrap_or(&[]);
+
+        let strings: Vec<&[u8]> = match section(SECTION_STRINGS) {
+            [] => vec![],
+            [s @ .., 0] => s.split(|b| *b == 0).collect(),
+            _ => return Err(ContainerErr::BadSection(SECTION_STRINGS)),
+        };
+        let name = |b: &[u8]| {
+            let i = u16::from_le_bytes([b[0], b[1]]) as usize;
+            strings
+                .get(i)
+                .map(|s| String::from_utf8_lossy(s).into_owned())
+                .ok_or(ContainerErr::BadString(i))
+        };
+        let funcs = section(SECTION_FUNCS);
+        if funcs.len() % 4 != 0 {
+            return Err(ContainerErr::BadSection(SECTION_FUNCS));
+        }
+        let funcs = funcs
+            .chunks(4)
+            .map(|e| {
+                Ok(FuncEntry {
+                    name: name(&e[2..])?,
+                    nparams: e[0] as usize,
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        let datas = section(SECTION_DATAS);
+        if datas.len() % 2 != 0 {
+            return Err(ContainerErr::BadSection(SECTION_DATAS));
+        }
+        let datas = datas.chunks(2).map(name).collect::<Result<_, _>>()?;
+        let drivers = section(SECTION_DRIVERS);
+        if drivers.len() % DRIVER_ENTRY_SIZE != 0 {
+            return Err(ContainerErr::BadSection(SECTION_DRIVERS));
+        }
+        let drivers = drivers
+            .chunks(DRIVER_ENTRY_SIZE)
+            .map(|e| {
+                let kind = match e[1] {
+                    1 => DriverKind::Input,
+                    2 => DriverKind::Output,
+                    _ => return Err(ContainerErr::BadSection(SECTION_DRIVERS)),
+                };
+                Ok(DriverEntry {
+                    node: e[0] as usize,
+                    kind,
+                    name: name(&e[2..])?,
+                    every_ms: u32_at(e, 4),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut deps = vec![];
+        let mut rest = section(SECTION_DEPS);
+        while let [node, always, n, tail @ ..] = rest {
+            let Some((users, tail)) = tail.split_at_checked(*n as usize) else {
+                break;
+            };
+            deps.push(DepEntry {
+                node: *node as usize,
+                always: *always != 0,
+                users: users.iter().map(|u| *u as usize).collect(),
+            });
+            rest = tail;
+        }
+        if !rest.is_empty() {
+            return Err(ContainerErr::BadSection(SECTION_DEPS));
+        }
+
+        let code = |kind| decode_insns(section(kind)).map_err(ContainerErr::Decode);
+        Ok(Image {
+            tick_ms: u32_at(bytes, 12),
+            init: code(SECTION_INIT)?,
+            update: code(SECTION_UPDATE)?,
+            funcs,
+            datas,
+            drivers,
+            deps,
+        })
+    }
+}
+
+// the init and update sections exactly as they are in the image, for the
+// machines that run the bytes themselves
+pub fn code_sections(bytes: &[u8]) -> Result<(&[u8], &[u8]), ContainerErr> {
+    let sections = sections(bytes)?;
+    let section = |kind: u8| sections[kind as usize].unwrap_or(&[]);
+    Ok((section(SECTION_INIT), section(SECTION_UPDATE)))
+}
+// the sections of a checked image by kind; missing ones are None
+fn sections(bytes: &[u8]) -> Result<[Option<&[u8]>; 8], ContainerErr> {
+    if bytes.len() < HEADER_SIZE + 4 {
+        return Err(ContainerErr::BadLength(bytes.len()));
+    }
+    if bytes[0..4] != MAGIC {
+        return Err(ContainerErr::BadMagic);
+    }
+    if bytes[4] != FORMAT_VERSION {
+        return Err(ContainerErr::UnsupportedFormat(bytes[4]));
+    }
+    if bytes[5] != OPCODE_SET_VERSION {
+        return Err(ContainerErr::UnsupportedOpcodeSet(bytes[5]));
+    }
+    let total = u32_at(bytes, 8) as usize;
+    if total != bytes.len() {
+        return Err(ContainerErr::BadLength(total));
+    }
+    let body = &bytes[..total - 4];
+    if crc32(body) != u32_at(bytes, total - 4) {
+        return Err(ContainerErr::BadChecksum);
+    }
+    let nsections = bytes[6] as usize;
+    if HEADER_SIZE + SECTION_ENTRY_SIZE * nsections > body.len() {
+        return Err(ContainerErr::BadLength(total));
+    }
+    let mut sections: [Option<&[u8]>; 8] = [None; 8];
+    for i in 0..nsections {
+        let entry = HEADER_SIZE + SECTION_ENTRY_SIZE * i;
+        let kind = bytes[entry];
+        let offset = u32_at(bytes, entry + 4) as usize;
+        let len = u32_at(bytes, entry + 8) as usize;
+        let Some(section) = body.get(offset..offset.saturating_add(len)) else {
+            return Err(ContainerErr::BadSection(kind));
+        };
+        match sections.get_mut(kind as usize) {
+            Some(s @ None) if kind != 0 => *s = Some(section),
+            // newer kinds are skipped so that older hosts can read the code
+            None => (),
+            _ => return Err(ContainerErr::BadSection(kind)),
+        }
+    }
+    Ok(sections)
+}
+
+fn u32_at(bytes: &[u8], i: usize) -> u32 {
+    u32::from_le_bytes([bytes[i], bytes[i + 1], bytes[i + 2], bytes[i + 3]])
+}
+
+// CRC-32/ISO-HDLC (the one of zlib), computed bitwise as in emfrp.c
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[test]
+fn container_test() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    let image = Image {
+        tick_ms: 50,
+        init: vec![
+            Insn::AllocFuncNew(vec![Insn::GetLocal(0), Insn::Return]),
+            Insn::Int(1),
+            Insn::AllocDataNew,
+            Insn::Halt,
+        ],
+        update: vec![Insn::SaveLast, Insn::Halt],
+        funcs: vec![FuncEntry {
+            name: "id".to_string(),
+            nparams: 1,
+        }],
+        datas: vec!["k".to_string()],
+        drivers: vec![DriverEntry {
+            node: 0,
+            kind: DriverKind::Input,
+            name: "temp".to_string(),
+            every_ms: 500,
+        }],
+        deps: vec![DepEntry {
+            node: 0,
+            always: true,
+            users: vec![],
+        }],
+    };
+    let bytes = image.encode();
+    assert_eq!(bytes[..6], *b"EMFR\x02\x02");
+    assert_eq!(
+        code_sections(&bytes),
+        Ok((
+            &encode_insns(&image.init)[..],
+            &encode_insns(&image.update)[..]
+        ))
+    );
+    assert_eq!(Image::decode(&bytes), Ok(image));
+
+    let mut broken = bytes.clone();
+    broken[HEADER_SIZE + SECTION_ENTRY_SIZE * 5] ^= 1;
+    assert_eq!(Image::decode(&broken), Err(ContainerErr::BadChecksum));
+    let mut newer = bytes.clone();
+    newer[5] = OPCODE_SET_VERSION + 1;
+    assert_eq!(
+        Image::decode(&newer),
+        Err(ContainerErr::UnsupportedOpcodeSet(OPCODE_SET_VERSION + 1))
+    );
+    let mut older = bytes.clone();
+    older[5] = OPCODE_SET_VERSION - 1;
+    assert_eq!(
+        Image::decode(&older),
+        Err(ContainerErr::UnsupportedOpcodeSet(OPCODE_SET_VERSION - 1))
+    );
+    assert_eq!(
+        Image::decode(&bytes[..bytes.len() - 1]),
+        Err(ContainerErr::BadLength(bytes.len()))
+    );
+}
@@ -14,6 +14,16 @@ impl<T> Default for List<T> {
         Self { head: None, len: 0 }
     }
 }
+impl<T: Clone> Clone for List<T> {
+    fn clone(&self) -> Self {
+        let mut v: Vec<&T> = self.iter().collect();
+        let mut ret = List::new();
+        while let Some(t) = v.pop() {
+            ret.push(t.clone());
+        }
+        ret
+    }
+}
 impl<T: Debug> Debug for List<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut f = f.debug_list();
@@ -54,7 +64,7 @@ impl<T> List<T> {
         self.len
     }
     pub fn is_empty(&self) -> bool {
-        matches!(&self.head, None)
+        self.head.is_none()
     }
     pub fn push(&mut self, t: T) {
         let newnd = Some(Box::new(Node {
@@ -65,7 +75,7 @@ impl<T> List<T> {
         self.len += 1;
     }
     pub fn pop(&mut self) -> Option<T> {
-        match std::mem::replace(&mut self.head, Link::None) {
+        match self.head.take() {
             Link::None => None,
             Link::Some(nd) => {
                 let Node { car, cdr } = *nd;
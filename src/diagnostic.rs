@@ -0,0 +1,229 @@
+use lalrpop_util::{lexer::Token, ParseError};
+
+use crate::ast::{Id, Span};
+use crate::compile::CompileErr;
+use crate::emtypes::TypeErr;
+use crate::MAX_NUMBER_OF_NODE;
+
+// an error message rendered like rustc:
+//
+// error: mismatched types: expected `Int`, found `Bool`
+//  --> <stdin>:1:5
+//   |
+// 1 | 1 + true
+//   |     ^^^^
+#[derive(Debug)]
+pub struct Diagnostic {
+    msg: String,
+    span: Option<Span>,
+    hint: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn new(msg: String, span: Option<Span>) -> Self {
+        Self {
+            msg,
+            span,
+            hint: None,
+        }
+    }
+    pub fn hint(mut self, hint: String) -> Self {
+        self.hint = Some(hint);
+        self
+    }
+    pub fn render(&self, file: &str, src: &str) -> String {
+        let mut ret = format!("error: {}\n", self.msg);
+        if let Some(span) = self.span {
+            let lo = span.lo.min(src.len());
+            let line_start = src[..lo].rfind('\n').map_or(0, |i| i + 1);
+            let line_end = src[lo..].find('\n').map_or(src.len(), |i| lo + i);
+            let line_no = src[..lo].matches('\n').count() + 1;
+            let col = src[line_start..lo].chars().count() + 1;
+            // spans over several lines are underlined up to the end of the first one
+            let width = src[lo..span.hi.clamp(lo, line_end)].chars().count().max(1);
+            let pad = " ".repeat(line_no.to_string().len());
+            ret.push_str(&format!("{pad}--> {file}:{line_no}:{col}\n"));
+            ret.push_str(&format!("{pad} |\n"));
+            ret.push_str(&format!("{line_no} | {}\n", &src[line_start..line_end]));
+            ret.push_str(&format!(
+                "{pad} | {}{}\n",
+                " ".repeat(col - 1),
+                "^".repeat(width)
+            ));
+        }
+        if let Some(hint) = &self.hint {
+            ret.push_str(&format!("  = hint: {hint}\n"));
+        }
+        ret
+    }
+}
+
+impl From<&CompileErr<'_>> for Diagnostic {
+    fn from(e: &CompileErr<'_>) -> Self {
+        match e {
+            CompileErr::IdNotFound(id) => id_not_found(id),
+            CompileErr::LastOfNonNode(id) => last_of_non_node(id),
+            CompileErr::ArityMismatch {
+                name,
+                expected,
+                found,
+            } => arity_mismatch(name, *expected, *found),
+            CompileErr::DuplicateDef(id) => Diagnostic::new(
+                format!("`{}` is defined more than once in this block", id.s),
+                Some(id.span),
+            ),
+            CompileErr::DuplicateTick(span) => Diagnostic::new(
+                "`tick` is given more than once in this block".to_string(),
+                Some(*span),
+            ),
+            CompileErr::CircularData { cycle, at } => {
+                let names: Vec<&str> = cycle.iter().map(|id| id.s.as_str()).collect();
+                Diagnostic::new(
+                    format!("circular reference between datas: {}", names.join(" -> ")),
+                    Some(at.span),
+                )
+            }
+            CompileErr::RecursiveFunc { cycle, at } => {
+                let names: Vec<&str> = cycle.iter().map(|id| id.s.as_str()).collect();
+                Diagnostic::new(
+                    format!("recursive function: {}", names.join(" -> ")),
+                    Some(at.span),
+                )
+                .hint("functions cannot call themselves, directly or through others".to_string())
+            }
+            CompileErr::CircularRef { cycle, at } => {
+                let names: Vec<&str> = cycle.iter().map(|id| id.s.as_str()).collect();
+                Diagnostic::new(
+                    format!("circular reference: {}", names.join(" -> ")),
+                    Some(at.span),
+                )
+                .hint(format!(
+                    "use `{}@last` to refer to the value of the previous update",
+                    names[1]
+                ))
+            }
+            CompileErr::NodeInUse { name, user } => Diagnostic::new(
+                format!("cannot delete `{}`: `{}` depends on it", name.s, user.s),
+                Some(name.span),
+            )
+            .hint(format!("delete or redefine `{}` first", user.s)),
+            CompileErr::TooManyNodes => Diagnostic::new(
+                format!("too many nodes (at most {} nodes)", MAX_NUMBER_OF_NODE),
+                None,
+            ),
+        }
+    }
+}
+
+impl From<&TypeErr<'_>> for Diagnostic {
+    fn from(e: &TypeErr<'_>) -> Self {
+        match e {
+            TypeErr::IdNotFound(id) => id_not_found(id),
+            TypeErr::Mismatch {
+                expected,
+                found,
+                span,
+            } => Diagnostic::new(
+                format!("mismatched types: expected `{expected}`, found `{found}`"),
+                Some(*span),
+            ),
+            TypeErr::CondNotBool(t, span) => Diagnostic::new(
+                format!("condition of `if` must be `Bool`, found `{t}`"),
+                Some(*span),
+            ),
+            TypeErr::BranchMismatch(t1, t2, span) => Diagnostic::new(
+                format!("`if` branches have different types: `{t1}` and `{t2}`"),
+                Some(*span),
+            )
+            .hint("`then` and `else` must have the same type".to_string()),
+            TypeErr::ArityMismatch {
+                name,
+                expected,
+                found,
+            } => arity_mismatch(name, *expected, *found),
+            TypeErr::LastOfNonNode(id) => last_of_non_node(id),
+            TypeErr::CannotInfer(id) => Diagnostic::new(
+                format!("cannot infer the type of node `{}`", id.s),
+                Some(id.span),
+            ),
+            TypeErr::IncompatibleRedefinition { name, dependent } => Diagnostic::new(
+                format!("redefinition changes the type of `{}`", name.s),
+                Some(name.span),
+            )
+            .hint(format!(
+                "`{}` depends on `{}`; redefine both in one `{{ ... }}` block",
+                dependent.s, name.s
+            )),
+        }
+    }
+}
+
+fn id_not_found(id: &Id) -> Diagnostic {
+    Diagnostic::new(
+        format!("cannot find `{}` in this scope", id.s),
+        Some(id.span),
+    )
+    .hint("define it with `node`, `data` or `func` first".to_string())
+}
+fn last_of_non_node(id: &Id) -> Diagnostic {
+    Diagnostic::new(format!("`{}` is not a node", id.s), Some(id.span))
+        .hint("`@last` can only be used on nodes, and not inside `func` or `data`".to_string())
+}
+fn arity_mismatch(name: &Id, expected: usize, found: usize) -> Diagnostic {
+    Diagnostic::new(
+        format!(
+            "function `{}` takes {} argument(s) but {} were supplied",
+            name.s, expected, found
+        ),
+        Some(name.span),
+    )
+}
+
+impl From<&ParseError<usize, Token<'_>, &str>> for Diagnostic {
+    fn from(e: &ParseError<usize, Token<'_>, &str>) -> Self {
+        let expected = |expected: &Vec<String>| format!("expected one of {}", expected.join(", "));
+        match e {
+            ParseError::InvalidToken { location } => Diagnostic::new(
+                "invalid token".to_string(),
+                Some(Span::new(*location, *location)),
+            ),
+            ParseError::UnrecognizedEof {
+                location,
+                expected: e,
+            } => Diagnostic::new(
+                "unexpected end of input".to_string(),
+                Some(Span::new(*location, *location)),
+            )
+            .hint(expected(e)),
+            ParseError::UnrecognizedToken {
+                token: (l, t, r),
+                expected: e,
+            } => Diagnostic::new(format!("unexpected `{}`", t.1), Some(Span::new(*l, *r)))
+                .hint(expected(e)),
+            ParseError::ExtraToken { token: (l, t, r) } => {
+                Diagnostic::new(format!("extra token `{}`", t.1), Some(Span::new(*l, *r)))
+            }
+            ParseError::User { error } => Diagnostic::new(error.to_string(), None),
+        }
+    }
+}
+
+#[test]
+fn render_test() {
+    let src = "node x\n  = 1 + true\n";
+    let lo = src.find("true").unwrap();
+    let d = Diagnostic::new("mismatched types".to_string(), Some(Span::new(lo, lo + 4)))
+        .hint("hint".to_string());
+    assert_eq!(
+        d.render("<stdin>", src),
+        "error: mismatched types\n --> <stdin>:2:9\n  |\n2 |   = 1 + true\n  |         ^^^^\n  = hint: hint\n"
+    );
+    // a literal that does not fit is an error rather than a panic
+    let e = crate::grammer::ProgramParser::new()
+        .parse("node a = 99999999999")
+        .unwrap_err();
+    assert_eq!(
+        Diagnostic::from(&e).render("<stdin>", "node a = 99999999999"),
+        "error: integer literal out of range\n"
+    );
+}
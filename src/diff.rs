@@ -0,0 +1,722 @@
+// differential testing of the Rust machine against emfrp.c, which build.rs
+// links into the test binary. both get the same uploads and must agree on
+// every node after every upload and update cycle
+use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+
+use crate::container::Image;
+use crate::machine::*;
+use crate::repl::{Session, Target};
+
+pub mod ffi {
+    use std::ffi::c_char;
+
+    extern "C" {
+        pub fn emfrp_set_new_code(code: *mut u8, len: i32) -> i32;
+        pub fn emfrp_update() -> i32;
+        pub fn emfrp_reset();
+        pub fn emfrp_node_count() -> i32;
+        pub fn emfrp_node_value(i: i32, v: *mut i32) -> i32;
+        pub fn emfrp_register_input(name: *const c_char, f: extern "C" fn(*mut i32));
+        pub fn emfrp_register_output(name: *const c_char, f: extern "C" fn(*mut i32));
+        pub fn emfrp_set_serial_output(f: Option<extern "C" fn(*mut u8, i32)>);
+        pub fn emfrp_serial_input(b: u8);
+    }
+}
+// emfrp.c keeps its state in globals
+static C_RUNTIME: Mutex<()> = Mutex::new(());
+
+// values of the live nodes by index, as the device sees them
+pub type Nodes = Vec<(usize, i32)>;
+// how a runtime gave up, which is what the two can be compared on since their
+// messages differ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    Rejected, // the container was refused
+    Load,     // the code was accepted but could not be loaded, e.g. init failed
+    Update,   // an update cycle failed
+}
+// the state after an upload or an update cycle
+pub type Step = Result<Nodes, (Failure, String)>;
+
+pub fn run_rust(uploads: &[Vec<u8>], cycles: usize) -> Vec<Step> {
+    let (machine, msgs) = Machine::new();
+    run_machine(machine, &msgs, uploads, cycles)
+}
+// for a machine with drivers registered
+pub fn run_machine(
+    mut machine: Machine,
+    msgs: &Receiver<String>,
+    uploads: &[Vec<u8>],
+    cycles: usize,
+) -> Vec<Step> {
+    machine.set_trace(false);
+    let mut ret = vec![];
+    for code in uploads {
+        machine.new_code(Code::Image(code.clone()));
+        let msg = msgs.recv().unwrap_or_default();
+        if !msg.starts_with("Node was defined") {
+            let failure = match Image::decode(code) {
+                Err(_) => Failure::Rejected,
+                Ok(_) => Failure::Load,
+            };
+            ret.push(Err((failure, msg)));
+            return ret;
+        }
+        ret.push(Ok(rust_nodes(&machine)));
+        for _ in 0..cycles {
+            if let Err(e) = machine.step(1) {
+                ret.push(Err((Failure::Update, e.to_string())));
+                return ret;
+            }
+            ret.push(Ok(rust_nodes(&machine)));
+        }
+    }
+    ret
+}
+
+fn rust_nodes(machine: &Machine) -> Nodes {
+    machine
+        .node_values()
+        .into_iter()
+        .map(|(i, v)| match v {
+            Value::Int(n) => (i, n),
+            Value::Bool(b) => (i, b as i32),
+            // nil is a null pointer on the device
+            _ => (i, 0),
+        })
+        .collect()
+}
+
+pub fn run_c(uploads: &[Vec<u8>], cycles: usize) -> Vec<Step> {
+    let _guard = C_RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+    let mut ret = vec![];
+    unsafe { ffi::emfrp_reset() };
+    'uploads: for code in uploads {
+        let mut code = code.clone();
+        let res = unsafe { ffi::emfrp_set_new_code(code.as_mut_ptr(), code.len() as i32) };
+        if res != 0 {
+            // LOAD_ERROR; the others are about the container
+            let failure = match res {
+                5 => Failure::Load,
+                _ => Failure::Rejected,
+            };
+            ret.push(Err((failure, format!("load_result_t {res}"))));
+            break;
+        }
+        ret.push(Ok(c_nodes()));
+        for _ in 0..cycles {
+            let res = unsafe { ffi::emfrp_update() };
+            if res != 0 {
+                ret.push(Err((Failure::Update, format!("exec_result_t {res}"))));
+                break 'uploads;
+            }
+            ret.push(Ok(c_nodes()));
+        }
+    }
+    unsafe { ffi::emfrp_reset() };
+    ret
+}
+// with C_RUNTIME locked
+fn c_nodes() -> Nodes {
+    let mut ret = vec![];
+    for i in 0..unsafe { ffi::emfrp_node_count() } {
+        let mut v = 0;
+        if unsafe { ffi::emfrp_node_value(i, &mut v) } != 0 {
+            ret.push((i as usize, v));
+        }
+    }
+    ret
+}
+
+// runs the uploads on both runtimes; Err describes the first difference.
+// failing at the same point counts as agreeing if it is for the same kind of
+// reason
+pub fn compare(uploads: &[Vec<u8>], cycles: usize) -> Result<(), String> {
+    let rust = run_rust(uploads, cycles);
+    let c = run_c(uploads, cycles);
+    let show = |step: Option<&Step>| match step {
+        None => "stopped".to_string(),
+        Some(Err((failure, e))) => format!("{failure:?} error: {e}"),
+        Some(Ok(nodes)) => {
+            let v: Vec<String> = nodes.iter().map(|(i, v)| format!("{i}: {v}")).collect();
+            format!("[{}]", v.join(", "))
+        }
+    };
+    for i in 0..rust.len().max(c.len()) {
+        let same = match (rust.get(i), c.get(i)) {
+            (Some(Ok(r)), Some(Ok(c))) => r == c,
+            (Some(Err((r, _))), Some(Err((c, _)))) => r == c,
+            _ => false,
+        };
+        if !same {
+            let (upload, cycle) = (i / (cycles + 1), i % (cycles + 1));
+            let at = match cycle {
+                0 => format!("after upload {upload}"),
+                n => format!("after cycle {n} of upload {upload}"),
+            };
+            return Err(format!(
+                "{at}\n  rust: {}\n  c:    {}",
+                show(rust.get(i)),
+                show(c.get(i))
+            ));
+        }
+    }
+    Ok(())
+}
+
+// compiles the sources in order, as the REPL would upload them
+pub fn compile(sources: &[String], on_change: bool) -> Result<Vec<Vec<u8>>, String> {
+    let mut session = Session::new(Target::None);
+    session.set_on_change(on_change);
+    sources
+        .iter()
+        .map(|src| session.compile("<generated>", src))
+        .collect()
+}
+
+// xorshift; the same seed always gives the same programs
+pub struct Rng(u64);
+impl Rng {
+    pub fn new(seed: u64) -> Self {
+        Rng(seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1)
+    }
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+    // one in n
+    fn chance(&mut self, n: usize) -> bool {
+        self.below(n) == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Ty {
+    Int,
+    Bool,
+}
+struct FuncSig {
+    name: String,
+    params: Vec<Ty>,
+    ret: Ty,
+    datas: usize, // reads datas before this index
+}
+struct NodeSig {
+    name: String,
+    ty: Ty,
+    init: bool, // only these are read with @last, since nil has no type
+}
+// what an expression may refer to
+#[derive(Clone, Copy)]
+enum Scope<'a> {
+    Data(usize), // data before this index
+    Func(&'a [(String, Ty)]),
+    Node(usize), // nodes before this index are read directly
+}
+
+// random well-typed programs, as a series of uploads that define new nodes,
+// functions and data and redefine old ones
+pub struct Generator {
+    rng: Rng,
+    funcs: Vec<FuncSig>,
+    datas: Vec<(String, Ty)>,
+    nodes: Vec<NodeSig>,
+}
+impl Generator {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            rng: Rng::new(seed),
+            funcs: vec![],
+            datas: vec![],
+            nodes: vec![],
+        }
+    }
+    fn ty(&mut self) -> Ty {
+        if self.rng.chance(3) {
+            Ty::Bool
+        } else {
+            Ty::Int
+        }
+    }
+
+    pub fn upload(&mut self) -> String {
+        let mut defs = vec![];
+        for _ in 0..self.rng.below(3) {
+            defs.push(self.func());
+        }
+        if !self.datas.is_empty() && self.rng.chance(3) {
+            let i = self.rng.below(self.datas.len());
+            let (name, ty) = self.datas[i].clone();
+            let val = self.exp(ty, 2, Scope::Data(i));
+            defs.push(format!("data {name} = {val}"));
+        }
+        for _ in 0..self.rng.below(3) {
+            let ty = self.ty();
+            let val = self.exp(ty, 2, Scope::Data(self.datas.len()));
+            let name = format!("d{}", self.datas.len());
+            defs.push(format!("data {name} = {val}"));
+            self.datas.push((name, ty));
+        }
+        if !self.nodes.is_empty() && self.rng.chance(2) {
+            let i = self.rng.below(self.nodes.len());
+            defs.push(self.node(i));
+        }
+        // names first so that any of them can be read with @last
+        let first = self.nodes.len();
+        for i in first..first + 1 + self.rng.below(4) {
+            let (ty, init) = (self.ty(), self.rng.chance(2));
+            let name = format!("n{i}");
+            self.nodes.push(NodeSig { name, ty, init });
+        }
+        for i in first..self.nodes.len() {
+            defs.push(self.node(i));
+        }
+        defs.join("\n")
+    }
+    fn func(&mut self) -> String {
+        let params: Vec<(String, Ty)> = (0..self.rng.below(3))
+            .map(|i| (format!("x{i}"), self.ty()))
+            .collect();
+        let ret = self.ty();
+        let body = self.exp(ret, 3, Scope::Func(&params));
+        let name = format!("f{}", self.funcs.len());
+        let names: Vec<&str> = params.iter().map(|(p, _)| p.as_str()).collect();
+        let def = format!("func {name}({}) = {body}", names.join(", "));
+        self.funcs.push(FuncSig {
+            name,
+            params: params.into_iter().map(|(_, t)| t).collect(),
+            ret,
+            datas: self.datas.len(),
+        });
+        def
+    }
+    fn node(&mut self, i: usize) -> String {
+        let ty = self.nodes[i].ty;
+        let init = match self.nodes[i].init {
+            true => format!("init[{}] ", self.exp(ty, 1, Scope::Data(self.datas.len()))),
+            false => String::new(),
+        };
+        let val = self.exp(ty, 3, Scope::Node(i));
+        format!("node {init}{} = {val}", self.nodes[i].name)
+    }
+
+    // the levels follow the grammar since there are no parentheses
+    fn exp(&mut self, ty: Ty, depth: usize, scope: Scope) -> String {
+        if depth > 0 && self.rng.chance(4) {
+            let cond = self.exp(Ty::Bool, depth - 1, scope);
+            let then = self.exp(ty, depth - 1, scope);
+            let els = self.exp(ty, depth - 1, scope);
+            return format!("if {cond} then {then} else {els}");
+        }
+        self.sum(ty, depth, scope)
+    }
+    fn sum(&mut self, ty: Ty, depth: usize, scope: Scope) -> String {
+        if ty == Ty::Int && depth > 0 && self.rng.chance(3) {
+            let e = self.sum(ty, depth - 1, scope);
+            let t = self.term(ty, depth - 1, scope);
+            return format!("{e} + {t}");
+        }
+        self.term(ty, depth, scope)
+    }
+    fn term(&mut self, ty: Ty, depth: usize, scope: Scope) -> String {
+        if ty == Ty::Int && depth > 0 && self.rng.chance(3) {
+            let t = self.term(ty, depth - 1, scope);
+            let f = self.factor(ty, depth - 1, scope);
+            return format!("{t} * {f}");
+        }
+        self.factor(ty, depth, scope)
+    }
+    fn factor(&mut self, ty: Ty, depth: usize, scope: Scope) -> String {
+        let datas = match scope {
+            Scope::Data(i) => i,
+            _ => self.datas.len(),
+        };
+        let mut vars: Vec<String> = self.datas[..datas]
+            .iter()
+            .filter(|(_, t)| *t == ty)
+            .map(|(d, _)| d.clone())
+            .collect();
+        match scope {
+            Scope::Data(_) => (),
+            Scope::Func(params) => vars.extend(
+                params
+                    .iter()
+                    .filter(|(_, t)| *t == ty)
+                    .map(|(p, _)| p.clone()),
+            ),
+            Scope::Node(i) => {
+                for (j, n) in self.nodes.iter().enumerate().filter(|(_, n)| n.ty == ty) {
+                    if j < i {
+                        vars.push(n.name.clone());
+                    }
+                    if n.init {
+                        vars.push(format!("{}@last", n.name));
+                    }
+                }
+            }
+        }
+        // a data may only call functions that read earlier datas
+        let funcs: Vec<usize> = (0..self.funcs.len())
+            .filter(|f| depth > 0 && self.funcs[*f].ret == ty && self.funcs[*f].datas <= datas)
+            .collect();
+        match self.rng.below(3) {
+            0 if !vars.is_empty() => vars.swap_remove(self.rng.below(vars.len())),
+            1 if !funcs.is_empty() => {
+                let f = funcs[self.rng.below(funcs.len())];
+                let params = self.funcs[f].params.clone();
+                let args: Vec<String> = params
+                    .into_iter()
+                    .map(|t| self.exp(t, depth - 1, scope))
+                    .collect();
+                format!("{}({})", self.funcs[f].name, args.join(", "))
+            }
+            _ => match ty {
+                Ty::Int => self.rng.below(20).to_string(),
+                Ty::Bool => (self.rng.below(2) == 0).to_string(),
+            },
+        }
+    }
+}
+
+#[test]
+fn known_divergences_test() {
+    // multiplication, bool literals, and more than two nodes
+    let src =
+        "node init[1] a = a@last * 3 node b = if true then a else 0 node c = b + a".to_string();
+    let uploads = compile(&[src], false).unwrap();
+    let steps = run_rust(&uploads, 2);
+    assert_eq!(steps[2], Ok(vec![(0, 9), (1, 9), (2, 18)]));
+    compare(&uploads, 2).unwrap();
+}
+
+#[test]
+fn failures_test() {
+    use crate::insn::Insn;
+    let src = "node init[1] a = a@last + 1".to_string();
+    let uploads = compile(&[src], false).unwrap();
+    // the checksum no longer matches
+    let mut broken = uploads.clone();
+    broken[0][20] ^= 1;
+    assert!(matches!(
+        run_rust(&broken, 1)[..],
+        [Err((Failure::Rejected, _))]
+    ));
+    compare(&broken, 1).unwrap();
+    // code for an older opcode set
+    let mut older = uploads.clone();
+    older[0][5] -= 1;
+    assert!(matches!(
+        run_c(&older, 1)[..],
+        [Err((Failure::Rejected, _))]
+    ));
+    compare(&older, 1).unwrap();
+    // a valid container whose init fails
+    let image = Image {
+        tick_ms: 0,
+        init: vec![Insn::Int(1), Insn::Halt],
+        update: vec![Insn::Halt],
+        funcs: vec![],
+        datas: vec![],
+        drivers: vec![],
+        deps: vec![],
+    };
+    let uploads = vec![image.encode()];
+    assert!(matches!(run_c(&uploads, 1)[..], [Err((Failure::Load, _))]));
+    compare(&uploads, 1).unwrap();
+    // dependencies on a node that the program does not have
+    let src = "input temp : Int output led = temp * 2".to_string();
+    let mut image = Image::decode(&compile(&[src], true).unwrap()[0]).unwrap();
+    image.deps[0].users.push(2);
+    let uploads = vec![image.encode()];
+    assert!(matches!(run_c(&uploads, 1)[..], [Err((Failure::Load, _))]));
+    compare(&uploads, 1).unwrap();
+}
+
+// a failed load leaves the previous program running, and the next upload finds
+// the indices that the compiler expects
+#[test]
+fn failed_load_test() {
+    use crate::insn::Insn;
+    let sources = [
+        "func f(x) = x + 1 data d = 5 node init[0] a = a@last + f(d)".to_string(),
+        "node b = a + d".to_string(),
+    ];
+    let good = compile(&sources, false).unwrap();
+    // changes every table, then halts with a value left on the stack
+    let bad = Image {
+        tick_ms: 0,
+        init: vec![
+            Insn::AllocFunc(0, vec![Insn::Int(100), Insn::Return]),
+            Insn::Int(50),
+            Insn::AllocData(0),
+            Insn::Int(7),
+            Insn::AllocNode(0, vec![Insn::Int(1000), Insn::Return]),
+            Insn::Int(0),
+            Insn::AllocNodeNew(vec![Insn::Int(2), Insn::Return]),
+            Insn::AllocFuncNew(vec![Insn::Int(1), Insn::Return]),
+            Insn::Int(1),
+            Insn::AllocDataNew,
+            Insn::Int(1),
+            Insn::Halt,
+        ],
+        update: vec![Insn::SaveLast, Insn::Halt],
+        funcs: vec![],
+        datas: vec![],
+        drivers: vec![],
+        deps: vec![],
+    }
+    .encode();
+    let uploads = [&good[0], &bad, &good[1]];
+
+    let (mut machine, msgs) = Machine::new();
+    machine.set_trace(false);
+    let mut rust = vec![];
+    for code in uploads {
+        machine.new_code(Code::Image(code.clone()));
+        rust.push((load_failed(&msgs.recv().unwrap()), vec![]));
+        machine.step(2).unwrap();
+        rust.push((false, rust_nodes(&machine)));
+    }
+    let mut c = vec![];
+    {
+        let _guard = C_RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe { ffi::emfrp_reset() };
+        for code in uploads {
+            let mut code = code.clone();
+            let res = unsafe { ffi::emfrp_set_new_code(code.as_mut_ptr(), code.len() as i32) };
+            c.push((res != 0, vec![]));
+            for _ in 0..2 {
+                assert_eq!(unsafe { ffi::emfrp_update() }, 0);
+            }
+            c.push((false, c_nodes()));
+        }
+        unsafe { ffi::emfrp_reset() };
+    }
+    assert_eq!(
+        rust,
+        [
+            (false, vec![]),
+            (false, vec![(0, 12)]),
+            (true, vec![]),
+            (false, vec![(0, 24)]),
+            (false, vec![]),
+            (false, vec![(0, 36), (1, 41)])
+        ]
+    );
+    assert_eq!(rust, c);
+}
+
+#[test]
+fn drivers_test() {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    static TEMP: AtomicI32 = AtomicI32::new(0);
+    static RUST_LED: AtomicI32 = AtomicI32::new(0);
+    static C_LED: AtomicI32 = AtomicI32::new(0);
+    fn temp() -> Value {
+        Value::Int(TEMP.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+    fn led(v: &Value) {
+        if let Value::Int(i) = v {
+            RUST_LED.store(*i, Ordering::SeqCst)
+        }
+    }
+    extern "C" fn c_temp(v: *mut i32) {
+        unsafe { *v += 1 }
+    }
+    extern "C" fn c_led(v: *mut i32) {
+        C_LED.store(unsafe { *v }, Ordering::SeqCst)
+    }
+    let src = "input temp : Int output led = temp * 2".to_string();
+    let uploads = compile(&[src], false).unwrap();
+    let (mut machine, msgs) = Machine::new();
+    machine.register_input("temp", temp);
+    machine.register_output("led", led);
+    let rust = run_machine(machine, &msgs, &uploads, 3);
+    {
+        let _guard = C_RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            ffi::emfrp_register_input(c"temp".as_ptr(), c_temp);
+            ffi::emfrp_register_output(c"led".as_ptr(), c_led);
+        }
+    }
+    let c = run_c(&uploads, 3);
+    assert_eq!(rust[3], Ok(vec![(0, 3), (1, 6)]));
+    assert_eq!(rust, c);
+    assert_eq!(RUST_LED.load(Ordering::SeqCst), 6);
+    assert_eq!(C_LED.load(Ordering::SeqCst), 6);
+}
+
+// inputs read less often than the tick keep their value in between
+#[test]
+fn sampling_test() {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    static SLOW: AtomicI32 = AtomicI32::new(0);
+    fn slow() -> Value {
+        Value::Int(SLOW.fetch_add(1, Ordering::SeqCst) + 1)
+    }
+    extern "C" fn c_slow(v: *mut i32) {
+        unsafe { *v += 1 }
+    }
+    let src = "tick 100ms input slow : Int every 250ms node twice = slow * 2".to_string();
+    let uploads = compile(&[src], false).unwrap();
+    let (mut machine, msgs) = Machine::new();
+    machine.register_input("slow", slow);
+    let rust = run_machine(machine, &msgs, &uploads, 6);
+    {
+        let _guard = C_RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe { ffi::emfrp_register_input(c"slow".as_ptr(), c_slow) }
+    }
+    let c = run_c(&uploads, 6);
+    // read at 100, 300 and 500ms
+    let slow: Vec<i32> = rust.iter().map(|s| s.as_ref().unwrap()[0].1).collect();
+    assert_eq!(slow, [0, 1, 1, 2, 2, 3, 3]);
+    assert_eq!(rust, c);
+}
+
+// an output whose input never changes is written once after the load
+#[test]
+fn on_change_test() {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    static RUST_WRITES: AtomicI32 = AtomicI32::new(0);
+    static C_WRITES: AtomicI32 = AtomicI32::new(0);
+    extern "C" fn c_still(_: *mut i32) {}
+    extern "C" fn c_shown(_: *mut i32) {
+        C_WRITES.fetch_add(1, Ordering::SeqCst);
+    }
+    let src = "input still : Int output shown = still + 1".to_string();
+    let uploads = compile(&[src], true).unwrap();
+    let (mut machine, msgs) = Machine::new();
+    machine.register_input("still", || Value::Int(0));
+    machine.register_output("shown", |_: &Value| {
+        RUST_WRITES.fetch_add(1, Ordering::SeqCst);
+    });
+    let rust = run_machine(machine, &msgs, &uploads, 5);
+    {
+        let _guard = C_RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            ffi::emfrp_register_input(c"still".as_ptr(), c_still);
+            ffi::emfrp_register_output(c"shown".as_ptr(), c_shown);
+        }
+    }
+    let c = run_c(&uploads, 5);
+    assert_eq!(rust, c);
+    assert_eq!(RUST_WRITES.load(Ordering::SeqCst), 1);
+    assert_eq!(C_WRITES.load(Ordering::SeqCst), 1);
+}
+
+// a changed input is passed on to its users, and only then
+#[test]
+fn on_change_input_test() {
+    use crate::driver::{Recorder, Replay};
+    const READINGS: [i32; 6] = [1, 1, 2, 2, 2, 3];
+    static C_READS: Mutex<usize> = Mutex::new(0);
+    static C_LED: Mutex<Vec<i32>> = Mutex::new(vec![]);
+    extern "C" fn c_count(v: *mut i32) {
+        let mut n = C_READS.lock().unwrap();
+        unsafe { *v = READINGS[(*n).min(READINGS.len() - 1)] };
+        *n += 1;
+    }
+    extern "C" fn c_led(v: *mut i32) {
+        C_LED.lock().unwrap().push(unsafe { *v })
+    }
+    let src = "input count : Int output led = count * 2".to_string();
+    let uploads = compile(&[src], true).unwrap();
+    let led = Recorder::new();
+    let (mut machine, msgs) = Machine::new();
+    machine.register_input(
+        "count",
+        Replay::new(READINGS.iter().map(|i| Value::Int(*i)).collect()),
+    );
+    machine.register_output("led", led.clone());
+    let rust = run_machine(machine, &msgs, &uploads, 6);
+    {
+        let _guard = C_RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+        unsafe {
+            ffi::emfrp_register_input(c"count".as_ptr(), c_count);
+            ffi::emfrp_register_output(c"led".as_ptr(), c_led);
+        }
+    }
+    let c = run_c(&uploads, 6);
+    assert_eq!(rust, c);
+    let written: Vec<i32> = led
+        .values()
+        .iter()
+        .map(|v| match v {
+            Value::Int(i) => *i,
+            v => panic!("{v}"),
+        })
+        .collect();
+    assert_eq!(written, [2, 4, 6]);
+    assert_eq!(*C_LED.lock().unwrap(), written);
+}
+
+#[test]
+fn random_programs_test() {
+    for seed in 0..200 {
+        let mut gen = Generator::new(seed);
+        let sources: Vec<String> = (0..1 + seed as usize % 3).map(|_| gen.upload()).collect();
+        let uploads = compile(&sources, false).unwrap_or_else(|e| {
+            panic!("seed {seed}: not compiled\n{e}\n{}", sources.join("\n--\n"))
+        });
+        if let Err(e) = compare(&uploads, 4) {
+            panic!("seed {seed}: {e}\n{}", sources.join("\n--\n"));
+        }
+        // updating only what changed must not change any value
+        let on_change = compile(&sources, true).unwrap();
+        if let Err(e) = compare(&on_change, 4) {
+            panic!("seed {seed}, on change: {e}\n{}", sources.join("\n--\n"));
+        }
+        let values = |steps: Vec<Step>| steps.into_iter().map(Result::ok).collect::<Vec<_>>();
+        assert_eq!(
+            values(run_rust(&uploads, 4)),
+            values(run_rust(&on_change, 4)),
+            "seed {seed}\n{}",
+            sources.join("\n--\n")
+        );
+    }
+}
+
+#[test]
+fn c_link_test() {
+    use crate::link::*;
+    static OUT: Mutex<Vec<u8>> = Mutex::new(vec![]);
+    extern "C" fn output(p: *mut u8, len: i32) {
+        let bytes = unsafe { std::slice::from_raw_parts(p, len as usize) };
+        OUT.lock().unwrap().extend(bytes);
+    }
+    let _guard = C_RUNTIME.lock().unwrap_or_else(|e| e.into_inner());
+    unsafe { ffi::emfrp_set_serial_output(Some(output)) };
+    // the load results answered to some frames, as in link::device_link_test
+    let send = |frames: &[Frame]| {
+        OUT.lock().unwrap().clear();
+        for b in frames.iter().flat_map(Frame::encode) {
+            unsafe { ffi::emfrp_serial_input(b) };
+        }
+        let mut decoder = FrameDecoder::new();
+        let out = OUT.lock().unwrap();
+        out.iter()
+            .filter_map(|b| decoder.push(*b)?.ok())
+            .filter(|f| f.kind == FrameKind::Result)
+            .map(|f| f.payload[0])
+            .collect::<Vec<u8>>()
+    };
+    let begin = |seq, len: u32| Frame::new(FrameKind::Begin, seq, len.to_le_bytes().to_vec());
+    let data = |seq, len| Frame::new(FrameKind::Data, seq, vec![7; len]);
+    assert_eq!(send(&[data(1, 8)]), [3]);
+    assert_eq!(send(&[Frame::new(FrameKind::Begin, 2, vec![8, 0])]), [3]);
+    assert_eq!(send(&[begin(3, MAX_UPLOAD as u32 + 1)]), [3]);
+    assert_eq!(send(&[begin(4, 4), data(5, 8)]), [3]);
+    // a complete upload, which is then found not to be a container
+    assert_eq!(send(&[begin(6, 20), data(7, 16), data(8, 4)]), [1]);
+    assert_eq!(send(&[data(9, 4)]), [3]);
+    unsafe {
+        ffi::emfrp_set_serial_output(None);
+        ffi::emfrp_reset();
+    }
+}
@@ -0,0 +1,216 @@
+use std::fmt::Write;
+
+use crate::container::*;
+use crate::insn::*;
+
+// prints a program in the format produced by Image::encode, e.g.
+//
+// tick: 50ms
+// init:
+//   0000  nil
+//   0001  allocnodenew {
+//         0000  int 1
+//         0005  return
+//         }
+//   0011  halt
+// update:
+//   ...
+// funcs:
+//   0  f/2
+// drivers:
+//   1  input temp every 500ms
+// deps:
+//   1  always  -> 2, 3
+//
+// offsets are relative to the start of the section (or of the node/func body),
+// which is what jump offsets are relative to as well
+pub fn disassemble(bytes: &[u8]) -> Result<String, ContainerErr> {
+    let image = Image::decode(bytes)?;
+    let mut ret = String::new();
+    if image.tick_ms != 0 {
+        writeln!(ret, "tick: {}ms", image.tick_ms).unwrap();
+    }
+    ret.push_str("init:\n");
+    write_insns(&mut ret, &image.init, 2);
+    ret.push_str("update:\n");
+    write_insns(&mut ret, &image.update, 2);
+    if !image.funcs.is_empty() {
+        ret.push_str("funcs:\n");
+        for (i, f) in image.funcs.iter().enumerate() {
+            writeln!(ret, "  {i}  {}/{}", f.name, f.nparams).unwrap();
+        }
+    }
+    if !image.datas.is_empty() {
+        ret.push_str("datas:\n");
+        for (i, d) in image.datas.iter().enumerate() {
+            writeln!(ret, "  {i}  {d}").unwrap();
+        }
+    }
+    if !image.drivers.is_empty() {
+        ret.push_str("drivers:\n");
+        for d in &image.drivers {
+            let kind = match d.kind {
+                DriverKind::Input => "input",
+                DriverKind::Output => "output",
+            };
+            write!(ret, "  {}  {kind} {}", d.node, d.name).unwrap();
+            if d.every_ms != 0 {
+                write!(ret, " every {}ms", d.every_ms).unwrap();
+            }
+            ret.push('\n');
+        }
+    }
+    if !image.deps.is_empty() {
+        ret.push_str("deps:\n");
+        for d in &image.deps {
+            write!(ret, "  {}", d.node).unwrap();
+            if d.always {
+                ret.push_str("  always");
+            }
+            if !d.users.is_empty() {
+                let users: Vec<String> = d.users.iter().map(|u| u.to_string()).collect();
+                write!(ret, "  -> {}", users.join(", ")).unwrap();
+            }
+            ret.push('\n');
+        }
+    }
+    Ok(ret)
+}
+
+pub fn write_insns(ret: &mut String, insns: &[Insn], indent: usize) {
+    let pad = " ".repeat(indent);
+    let mut offset = 0;
+    for insn in insns {
+        let len = bytecode_len(std::slice::from_ref(insn));
+        write!(ret, "{pad}{offset:04x}  {}", mnemonic(insn)).unwrap();
+        match insn {
+            Insn::Int(i) | Insn::Je32(i) | Insn::J32(i) => write!(ret, " {i}").unwrap(),
+            Insn::Je8(i) | Insn::J8(i) => write!(ret, " {i}").unwrap(),
+            Insn::Bool(b) => write!(ret, " {b}").unwrap(),
+            Insn::GetLocal(i)
+            | Insn::SetLocal(i)
+            | Insn::AllocNode(i, _)
+            | Insn::AllocFunc(i, _)
+            | Insn::AllocData(i)
+            | Insn::UpdateNode(i)
+            | Insn::GetNode(i)
+            | Insn::SetNode(i)
+            | Insn::GetLast(i)
+            | Insn::Call(i)
+            | Insn::GetFunc(i)
+            | Insn::GetData(i)
+            | Insn::FreeNode(i) => write!(ret, " {i}").unwrap(),
+            _ => (),
+        }
+        match insn {
+            Insn::Je8(_) | Insn::Je32(_) | Insn::J8(_) | Insn::J32(_) => {
+                let target = offset as i64 + len as i64 + jump_offset(insn) as i64;
+                writeln!(ret, "  ; -> {target:04x}").unwrap()
+            }
+            Insn::AllocNode(_, body)
+            | Insn::AllocNodeNew(body)
+            | Insn::AllocFunc(_, body)
+            | Insn::AllocFuncNew(body) => {
+                ret.push_str(" {\n");
+                write_insns(ret, body, indent + 6);
+                writeln!(ret, "{pad}      }}").unwrap();
+            }
+            _ => ret.push('\n'),
+        }
+        offset += len;
+    }
+}
+
+fn jump_offset(insn: &Insn) -> i32 {
+    match insn {
+        Insn::Je8(i) | Insn::J8(i) => *i as i32,
+        Insn::Je32(i) | Insn::J32(i) => *i,
+        _ => 0,
+    }
+}
+
+pub fn mnemonic(insn: &Insn) -> &'static str {
+    match insn {
+        Insn::None => "none",
+        Insn::Nil => "nil",
+        Insn::Int(_) => "int",
+        Insn::Bool(_) => "bool",
+        Insn::Add => "add",
+        Insn::Mul => "mul",
+        Insn::Je8(_) => "je8",
+        Insn::Je32(_) => "je32",
+        Insn::J8(_) => "j8",
+        Insn::J32(_) => "j32",
+        Insn::GetLocal(_) => "getlocal",
+        Insn::SetLocal(_) => "setlocal",
+        Insn::Halt => "halt",
+        Insn::AllocNode(_, _) => "allocnode",
+        Insn::AllocNodeNew(_) => "allocnodenew",
+        Insn::AllocFunc(_, _) => "allocfunc",
+        Insn::AllocFuncNew(_) => "allocfuncnew",
+        Insn::AllocData(_) => "allocdata",
+        Insn::AllocDataNew => "allocdatanew",
+        Insn::Return => "return",
+        Insn::Call(_) => "call",
+        Insn::GetFunc(_) => "getfunc",
+        Insn::GetData(_) => "getdata",
+        Insn::UpdateNode(_) => "updatenode",
+        Insn::GetNode(_) => "getnode",
+        Insn::SetNode(_) => "setnode",
+        Insn::GetLast(_) => "getlast",
+        Insn::SaveLast => "savelast",
+        Insn::FreeNode(_) => "freenode",
+        Insn::Exit => "exit",
+        Insn::Placeholder => "placeholder",
+    }
+}
+
+#[test]
+fn disassemble_test() {
+    let init = vec![
+        Insn::Nil,
+        Insn::AllocNodeNew(vec![
+            Insn::Bool(true),
+            Insn::Je8(7),
+            Insn::Int(1),
+            Insn::J8(5),
+            Insn::Int(2),
+            Insn::Return,
+        ]),
+        Insn::Halt,
+    ];
+    let update = vec![
+        Insn::SaveLast,
+        Insn::UpdateNode(0),
+        Insn::SetNode(0),
+        Insn::Halt,
+    ];
+    let image = Image {
+        init,
+        update,
+        datas: vec!["k".to_string()],
+        ..Default::default()
+    };
+    assert_eq!(
+        disassemble(&image.encode()).unwrap(),
+        "init:
+  0000  nil
+  0001  allocnodenew {
+        0000  bool true
+        0002  je8 7  ; -> 000b
+        0004  int 1
+        0009  j8 5  ; -> 0010
+        000b  int 2
+        0010  return
+        }
+  0017  halt
+update:
+  0000  savelast
+  0001  updatenode 0
+  0003  setnode 0
+  0005  halt
+datas:
+  0  k
+"
+    );
+}
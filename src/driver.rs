@@ -0,0 +1,165 @@
+// drivers for the I/O nodes of the Rust machine, registered by name with
+// Machine::register_input/register_output. the mock ones stand in for sensors
+// and actuators so that whole programs can be run deterministically
+use std::sync::{Arc, Mutex};
+
+use crate::machine::Value;
+
+// read once per update cycle of the node
+pub trait InputDriver: Send {
+    fn read(&mut self) -> Value;
+}
+// called with every value set to the node
+pub trait OutputDriver: Send {
+    fn write(&mut self, v: &Value);
+}
+impl<F: FnMut() -> Value + Send> InputDriver for F {
+    fn read(&mut self) -> Value {
+        self()
+    }
+}
+impl<F: FnMut(&Value) + Send> OutputDriver for F {
+    fn write(&mut self, v: &Value) {
+        self(v)
+    }
+}
+
+// plays back recorded readings; the last one is held once they run out
+pub struct Replay {
+    values: Vec<Value>,
+    next: usize,
+}
+impl Replay {
+    pub fn new(values: Vec<Value>) -> Self {
+        Self { values, next: 0 }
+    }
+    // the column of that name in a CSV with a header line, e.g.
+    //
+    //   time,temp,door
+    //   0,21,false
+    //   1000,22,true
+    pub fn from_csv(csv: &str, column: &str) -> Result<Self, String> {
+        let mut lines = csv.lines().filter(|l| !l.trim().is_empty());
+        let header = lines.next().ok_or("empty csv")?;
+        let col = header
+            .split(',')
+            .position(|h| h.trim() == column)
+            .ok_or_else(|| format!("no column `{column}`"))?;
+        let mut values = vec![];
+        for (i, line) in lines.enumerate() {
+            let field = line.split(',').nth(col).map(str::trim);
+            let v = match field {
+                Some("true") => Value::Bool(true),
+                Some("false") => Value::Bool(false),
+                Some(s) => s
+                    .parse()
+                    .map(Value::Int)
+                    .map_err(|_| format!("row {}: invalid reading `{s}`", i + 1))?,
+                None => return Err(format!("row {}: no column `{column}`", i + 1)),
+            };
+            values.push(v);
+        }
+        Ok(Self::new(values))
+    }
+}
+impl InputDriver for Replay {
+    fn read(&mut self) -> Value {
+        let v = match self.values.get(self.next).or(self.values.last()) {
+            Some(v) => v.clone(),
+            None => Value::Nil,
+        };
+        self.next += 1;
+        v
+    }
+}
+
+// offset + amplitude * sin(2 pi n / period) for the n-th reading, rounded
+pub struct Sine {
+    pub offset: i32,
+    pub amplitude: f64,
+    pub period: u32, // in readings
+    n: u32,
+}
+impl Sine {
+    pub fn new(offset: i32, amplitude: f64, period: u32) -> Self {
+        Self {
+            offset,
+            amplitude,
+            period: period.max(1),
+            n: 0,
+        }
+    }
+}
+impl InputDriver for Sine {
+    fn read(&mut self) -> Value {
+        let phase = std::f64::consts::TAU * self.n as f64 / self.period as f64;
+        self.n = (self.n + 1) % self.period;
+        Value::Int(self.offset + (self.amplitude * phase.sin()).round() as i32)
+    }
+}
+
+// a button pressed during the readings marked `#` in a pattern such as
+// "__##___#", and released during the others and after the end
+pub struct Button {
+    pattern: Vec<bool>,
+    next: usize,
+}
+impl Button {
+    pub fn new(pattern: &str) -> Self {
+        Self {
+            pattern: pattern.chars().map(|c| c == '#').collect(),
+            next: 0,
+        }
+    }
+}
+impl InputDriver for Button {
+    fn read(&mut self) -> Value {
+        let pressed = self.pattern.get(self.next).copied().unwrap_or(false);
+        self.next += 1;
+        Value::Bool(pressed)
+    }
+}
+
+// keeps every value written; clones share the record, so one can be
+// registered and another kept to look at the values
+#[derive(Clone, Default)]
+pub struct Recorder(Arc<Mutex<Vec<Value>>>);
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn values(&self) -> Vec<Value> {
+        self.0.lock().unwrap().clone()
+    }
+}
+impl OutputDriver for Recorder {
+    fn write(&mut self, v: &Value) {
+        self.0.lock().unwrap().push(v.clone())
+    }
+}
+
+#[test]
+fn mock_drivers_test() {
+    use crate::machine::test_machine;
+    let src = "input temp : Int
+input door : Bool
+input wave : Int
+output alarm = if door then temp else 0
+output level = wave + 10";
+    let csv = "time,temp\n0,21\n1000,22\n2000,25\n";
+    let (alarm, level) = (Recorder::new(), Recorder::new());
+    let mut machine = test_machine(src, false, |machine| {
+        machine.register_input("temp", Replay::from_csv(csv, "temp").unwrap());
+        machine.register_input("door", Button::new("_##_"));
+        machine.register_input("wave", Sine::new(0, 10.0, 4));
+        machine.register_output("alarm", alarm.clone());
+        machine.register_output("level", level.clone());
+    });
+    machine.step(5).unwrap();
+    let ints = |v: &[i32]| v.iter().map(|i| Value::Int(*i)).collect::<Vec<_>>();
+    assert_eq!(alarm.values(), ints(&[0, 22, 25, 0, 0]));
+    assert_eq!(level.values(), ints(&[10, 20, 10, 0, 10]));
+
+    assert!(Replay::from_csv(csv, "hum").is_err());
+    assert!(Replay::from_csv("temp\nwarm\n", "temp").is_err());
+}
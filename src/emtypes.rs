@@ -1 +1,469 @@
+use std::collections::HashMap;
+use std::fmt::Display;
+
+use crate::ast::*;
+
 pub type Num = i32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Type {
+    Int,
+    Bool,
+    Func(Vec<Type>, Box<Type>),
+    Var(usize),
+}
+
+#[derive(Debug)]
+pub enum TypeErr<'a> {
+    IdNotFound(&'a Id),
+    Mismatch {
+        expected: Type,
+        found: Type,
+        span: Span,
+    },
+    CondNotBool(Type, Span),
+    BranchMismatch(Type, Type, Span),
+    ArityMismatch {
+        name: &'a Id,
+        expected: usize,
+        found: usize,
+    },
+    LastOfNonNode(&'a Id),
+    CannotInfer(&'a Id),
+    // redefinition of `name` changes its type while `dependent` still uses it
+    IncompatibleRedefinition {
+        name: &'a Id,
+        dependent: Id,
+    },
+}
+type TResult<'a, T> = Result<T, TypeErr<'a>>;
+
+// types of everything defined so far; kept across REPL inputs
+#[derive(Debug, Clone, Default)]
+pub struct TypeEnv {
+    nodes: HashMap<Id, Type>,
+    datas: HashMap<Id, Type>,
+    funcs: HashMap<Id, Type>, // type variables left in a function type are generalized
+    deps: HashMap<Id, Vec<Id>>,
+}
+
+struct Checker {
+    env: TypeEnv,
+    subst: Vec<Option<Type>>,
+    deps: Vec<Id>,
+    mono: Vec<Id>, // funcs of the program being checked, not generalized yet
+}
+
+impl TypeEnv {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // returns the environment extended by prog (self is left untouched so that
+    // the caller can discard it if a later stage fails) and the type of an expression
+    pub fn check<'a>(&self, prog: &'a Program) -> TResult<'a, (TypeEnv, Option<Type>)> {
+        let mut c = Checker {
+            env: self.clone(),
+            subst: vec![],
+            deps: vec![],
+            mono: vec![],
+        };
+        let defs = match prog {
+            Program::Exp(e) => {
+                let t = c.infer_exp(e, None)?;
+                let t = c.resolve(&t);
+                return Ok((c.env, Some(t)));
+            }
+            Program::Def(def) => std::slice::from_ref(def),
+            Program::Defs(defs) => &defs[..],
+            Program::Delete(name) => {
+                // whether other nodes still use it is checked by the compiler
+                if c.env.nodes.remove(name).is_none() {
+                    return Err(TypeErr::IdNotFound(name));
+                }
+                c.env.deps.remove(name);
+                return Ok((c.env, None));
+            }
+        };
+
+        // nodes may refer to each other in any order
+        for def in defs {
+            if let Def::Node { name, .. } = def {
+                let t = c.fresh();
+                c.env.nodes.insert(name.clone(), t);
+            }
+        }
+        // so may funcs and datas, and a func may call itself. within the program
+        // their types are not generalized until every body has been inferred
+        for def in defs {
+            match def {
+                Def::Data { name, .. } => {
+                    let t = c.fresh();
+                    c.env.datas.insert(name.clone(), t);
+                }
+                Def::Func { name, params, .. } => {
+                    let params = params.iter().map(|_| c.fresh()).collect();
+                    let t = Type::Func(params, Box::new(c.fresh()));
+                    c.env.funcs.insert(name.clone(), t);
+                    c.mono.push(name.clone());
+                }
+                Def::Node { .. } | Def::Tick { .. } => (),
+            }
+        }
+        for def in defs {
+            let name = match def {
+                Def::Data { name, val } => {
+                    let t = c.env.datas[name].clone();
+                    let found = c.infer_exp(val, Some(&[]))?;
+                    c.expect(&t, &found, val.span())?;
+                    name
+                }
+                Def::Func { name, params, body } => {
+                    let Type::Func(types, ret) = c.env.funcs[name].clone() else {
+                        unreachable!()
+                    };
+                    let params: Vec<(Id, Type)> = params.iter().cloned().zip(types).collect();
+                    let found = c.infer_exp(body, Some(&params))?;
+                    c.expect(&ret, &found, body.span())?;
+                    name
+                }
+                Def::Node { .. } | Def::Tick { .. } => continue,
+            };
+            let deps = std::mem::take(&mut c.deps);
+            c.env.deps.insert(name.clone(), deps);
+        }
+        for def in defs {
+            match def {
+                Def::Data { name, .. } => {
+                    let t = c.resolve(&c.env.datas[name]);
+                    if matches!(t, Type::Var(_)) {
+                        return Err(TypeErr::CannotInfer(name));
+                    }
+                    c.env.datas.insert(name.clone(), t);
+                }
+                Def::Func { name, .. } => {
+                    let t = normalize(&c.resolve(&c.env.funcs[name]));
+                    c.env.funcs.insert(name.clone(), t);
+                }
+                Def::Node { .. } | Def::Tick { .. } => (),
+            }
+        }
+        c.mono.clear();
+        for def in defs {
+            if let Def::Node {
+                name, init, val, ..
+            } = def
+            {
+                let t = c.env.nodes[name].clone();
+                let found = c.infer_exp(val, None)?;
+                c.expect(&t, &found, val.span())?;
+                if let Some(init) = init {
+                    let found = c.infer_exp(init, None)?;
+                    c.expect(&t, &found, init.span())?;
+                }
+                let deps = std::mem::take(&mut c.deps);
+                c.env.deps.insert(name.clone(), deps);
+            }
+        }
+        for def in defs {
+            if let Def::Node { name, .. } = def {
+                let t = c.resolve(&c.env.nodes[name]);
+                if matches!(t, Type::Var(_)) {
+                    return Err(TypeErr::CannotInfer(name));
+                }
+                c.env.nodes.insert(name.clone(), t);
+            }
+        }
+
+        for def in defs {
+            let (name, old, new) = match def {
+                Def::Node { name, .. } => (name, self.nodes.get(name), c.env.nodes.get(name)),
+                Def::Data { name, .. } => (name, self.datas.get(name), c.env.datas.get(name)),
+                Def::Func { name, .. } => (name, self.funcs.get(name), c.env.funcs.get(name)),
+                Def::Tick { .. } => continue,
+            };
+            if old.is_none() || old == new {
+                continue;
+            }
+            for (dependent, deps) in &self.deps {
+                if deps.contains(name) && defs.iter().all(|d| d.name() != Some(dependent)) {
+                    return Err(TypeErr::IncompatibleRedefinition {
+                        name,
+                        dependent: dependent.clone(),
+                    });
+                }
+            }
+        }
+        Ok((c.env, None))
+    }
+}
+
+impl Checker {
+    fn fresh(&mut self) -> Type {
+        self.subst.push(None);
+        Type::Var(self.subst.len() - 1)
+    }
+    fn resolve(&self, t: &Type) -> Type {
+        match t {
+            Type::Var(v) => match &self.subst[*v] {
+                Some(t) => self.resolve(t),
+                None => Type::Var(*v),
+            },
+            Type::Func(params, ret) => Type::Func(
+                params.iter().map(|p| self.resolve(p)).collect(),
+                Box::new(self.resolve(ret)),
+            ),
+            _ => t.clone(),
+        }
+    }
+    fn occurs(&self, v: usize, t: &Type) -> bool {
+        match self.resolve(t) {
+            Type::Var(u) => u == v,
+            Type::Func(params, ret) => {
+                params.iter().any(|p| self.occurs(v, p)) || self.occurs(v, &ret)
+            }
+            _ => false,
+        }
+    }
+    fn unify(&mut self, t1: &Type, t2: &Type) -> bool {
+        match (self.resolve(t1), self.resolve(t2)) {
+            (Type::Var(u), Type::Var(v)) if u == v => true,
+            (Type::Var(v), t) | (t, Type::Var(v)) => {
+                if self.occurs(v, &t) {
+                    return false;
+                }
+                self.subst[v] = Some(t);
+                true
+            }
+            (Type::Func(p1, r1), Type::Func(p2, r2)) => {
+                p1.len() == p2.len()
+                    && p1.iter().zip(p2.iter()).all(|(a, b)| self.unify(a, b))
+                    && self.unify(&r1, &r2)
+            }
+            (t1, t2) => t1 == t2,
+        }
+    }
+    fn expect<'a>(&mut self, expected: &Type, found: &Type, span: Span) -> TResult<'a, ()> {
+        if self.unify(expected, found) {
+            Ok(())
+        } else {
+            Err(TypeErr::Mismatch {
+                expected: self.resolve(expected),
+                found: self.resolve(found),
+                span,
+            })
+        }
+    }
+    fn instantiate(&mut self, t: &Type, vars: &mut HashMap<usize, Type>) -> Type {
+        match t {
+            Type::Var(v) => match vars.get(v) {
+                Some(t) => t.clone(),
+                None => {
+                    let t = self.fresh();
+                    vars.insert(*v, t.clone());
+                    t
+                }
+            },
+            Type::Func(params, ret) => Type::Func(
+                params.iter().map(|p| self.instantiate(p, vars)).collect(),
+                Box::new(self.instantiate(ret, vars)),
+            ),
+            _ => t.clone(),
+        }
+    }
+
+    // locals is None in node context and Some(params) inside functions and data
+    fn infer_exp<'a>(&mut self, exp: &'a Exp, locals: Option<&[(Id, Type)]>) -> TResult<'a, Type> {
+        match exp {
+            Exp::If {
+                cond,
+                then,
+                els,
+                span,
+            } => {
+                let t = self.infer_exp(cond, locals)?;
+                if !self.unify(&t, &Type::Bool) {
+                    return Err(TypeErr::CondNotBool(self.resolve(&t), cond.span()));
+                }
+                let t1 = self.infer_exp(then, locals)?;
+                let t2 = self.infer_exp(els, locals)?;
+                if self.unify(&t1, &t2) {
+                    Ok(t1)
+                } else {
+                    Err(TypeErr::BranchMismatch(
+                        self.resolve(&t1),
+                        self.resolve(&t2),
+                        *span,
+                    ))
+                }
+            }
+            Exp::Add(e, t) => {
+                let found = self.infer_exp(e, locals)?;
+                self.expect(&Type::Int, &found, e.span())?;
+                let found = self.infer_term(t, locals)?;
+                self.expect(&Type::Int, &found, t.span())?;
+                Ok(Type::Int)
+            }
+            Exp::Term(t) => self.infer_term(t, locals),
+        }
+    }
+    fn infer_term<'a>(
+        &mut self,
+        term: &'a Term,
+        locals: Option<&[(Id, Type)]>,
+    ) -> TResult<'a, Type> {
+        match term {
+            Term::Mul(t1, t2) => {
+                let found = self.infer_term(t1, locals)?;
+                self.expect(&Type::Int, &found, t1.span())?;
+                let found = self.infer_term(t2, locals)?;
+                self.expect(&Type::Int, &found, t2.span())?;
+                Ok(Type::Int)
+            }
+            Term::Int(..) => Ok(Type::Int),
+            Term::Bool(..) => Ok(Type::Bool),
+            Term::FnCall(f, args, _) => {
+                let Some(t) = self.env.funcs.get(f).cloned() else {
+                    return Err(TypeErr::IdNotFound(f));
+                };
+                self.deps.push((**f).clone());
+                let t = if self.mono.contains(f) {
+                    t
+                } else {
+                    self.instantiate(&t, &mut HashMap::new())
+                };
+                let Type::Func(params, ret) = t else {
+                    unreachable!()
+                };
+                if params.len() != args.len() {
+                    return Err(TypeErr::ArityMismatch {
+                        name: f,
+                        expected: params.len(),
+                        found: args.len(),
+                    });
+                }
+                for (param, arg) in params.iter().zip(args) {
+                    let found = self.infer_exp(arg, locals)?;
+                    self.expect(param, &found, arg.span())?;
+                }
+                Ok(*ret)
+            }
+            Term::Last(id, _) => match self.env.nodes.get(id) {
+                Some(t) if locals.is_none() => {
+                    self.deps.push(id.clone());
+                    Ok(t.clone())
+                }
+                _ => Err(TypeErr::LastOfNonNode(id)),
+            },
+            Term::Id(id) => {
+                if let Some((_, t)) = locals.and_then(|l| l.iter().find(|(p, _)| p == id)) {
+                    return Ok(t.clone());
+                }
+                let t = match self.env.nodes.get(id).filter(|_| locals.is_none()) {
+                    Some(t) => t.clone(),
+                    None => match self.env.datas.get(id) {
+                        Some(t) => t.clone(),
+                        None => return Err(TypeErr::IdNotFound(id)),
+                    },
+                };
+                self.deps.push(id.clone());
+                Ok(t)
+            }
+        }
+    }
+}
+
+// renumber type variables in order of appearance so that equal types compare equal
+fn normalize(t: &Type) -> Type {
+    fn helper(t: &Type, vars: &mut Vec<usize>) -> Type {
+        match t {
+            Type::Var(v) => match vars.iter().position(|u| u == v) {
+                Some(i) => Type::Var(i),
+                None => {
+                    vars.push(*v);
+                    Type::Var(vars.len() - 1)
+                }
+            },
+            Type::Func(params, ret) => Type::Func(
+                params.iter().map(|p| helper(p, vars)).collect(),
+                Box::new(helper(ret, vars)),
+            ),
+            _ => t.clone(),
+        }
+    }
+    helper(t, &mut vec![])
+}
+
+impl Display for Type {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Type::Int => write!(f, "Int"),
+            Type::Bool => write!(f, "Bool"),
+            Type::Var(v) => write!(f, "'t{}", v),
+            Type::Func(params, ret) => {
+                write!(f, "(")?;
+                for (i, p) in params.iter().enumerate() {
+                    if i != 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", p)?;
+                }
+                write!(f, ") -> {}", ret)
+            }
+        }
+    }
+}
+
+#[test]
+fn typecheck_test() {
+    let parser = crate::grammer::ProgramParser::new();
+    let env = TypeEnv::new();
+    for src in [
+        "1 + true",
+        "if 1 then 2 else 3",
+        "if true then 2 else false",
+        "func f(a) = a + 1 node x = f(true)",
+        "func f(a, b) = a node x = f(1)",
+        "node x = x@last",
+        "func f(a) = f(true) + a",
+        "data a = b data b = a",
+    ] {
+        let prog = parser.parse(src).unwrap();
+        assert!(env.check(&prog).is_err(), "{}", src);
+    }
+    let prog = parser
+        .parse("func id(a) = a node init[0] x = id(x@last) + 1 node b = id(true)")
+        .unwrap();
+    let (env, _) = env.check(&prog).unwrap();
+    let b = Id {
+        s: "b".to_string(),
+        span: Span::default(),
+    };
+    assert_eq!(env.nodes[&b], Type::Bool);
+
+    // changing the type of x breaks y
+    let prog = parser.parse("node y = x * 2").unwrap();
+    let (env, _) = env.check(&prog).unwrap();
+    let prog = parser.parse("node x = true").unwrap();
+    assert!(matches!(
+        env.check(&prog),
+        Err(TypeErr::IncompatibleRedefinition { .. })
+    ));
+    let prog = parser.parse("node x = 3").unwrap();
+    assert!(env.check(&prog).is_ok());
+
+    // calls to funcs and datas defined later, and recursion
+    let prog = parser
+        .parse(
+            "data d = g(2) + e data e = 1 func g(a) = h(a) + 1 func h(a) = a func r(n) = r(n) * n",
+        )
+        .unwrap();
+    let (env, _) = TypeEnv::new().check(&prog).unwrap();
+    let id = |s: &str| Id {
+        s: s.to_string(),
+        span: Span::default(),
+    };
+    assert_eq!(env.datas[&id("d")], Type::Int);
+    let int_to_int = Type::Func(vec![Type::Int], Box::new(Type::Int));
+    assert_eq!(env.funcs[&id("h")], int_to_int);
+    assert_eq!(env.funcs[&id("r")], int_to_int);
+}
@@ -19,15 +19,18 @@ pub enum Insn {
     AllocNodeNew(Vec<Insn>),
     AllocFunc(FuncOffset, Vec<Insn>),
     AllocFuncNew(Vec<Insn>),
-    AllocData(DataOffset, Vec<Insn>),
-    AllocDataNew(Vec<Insn>),
+    AllocData(DataOffset),
+    AllocDataNew,
     Return,
     Call(NArgs),
+    GetFunc(FuncOffset),
+    GetData(DataOffset),
     UpdateNode(NodeOffset),
     GetNode(NodeOffset),
     SetNode(NodeOffset),
     GetLast(NodeOffset),
     SaveLast,
+    FreeNode(NodeOffset),
     Exit,
     Placeholder,
 }
@@ -37,38 +40,78 @@ pub type StackOffset = usize;
 pub type FuncOffset = usize;
 pub type DataOffset = usize;
 
+// opcodes, the same as enum bytecode in emfrp.c
+pub mod op {
+    pub const NONE: u8 = 0;
+    pub const NIL: u8 = 1;
+    pub const INT: u8 = 2;
+    pub const BOOL: u8 = 3;
+    pub const ADD: u8 = 4;
+    pub const MUL: u8 = 5;
+    pub const JE8: u8 = 6;
+    pub const JE32: u8 = 7;
+    pub const J8: u8 = 8;
+    pub const J32: u8 = 9;
+    pub const GET_LOCAL: u8 = 10;
+    pub const SET_LOCAL: u8 = 11;
+    pub const ALLOC_NODE: u8 = 12;
+    pub const ALLOC_NODE_NEW: u8 = 13;
+    pub const UPDATE_NODE: u8 = 14;
+    pub const GET_NODE: u8 = 15;
+    pub const SET_NODE: u8 = 16;
+    pub const GET_LAST: u8 = 17;
+    pub const SAVE_LAST: u8 = 18;
+    pub const ALLOC_FUNC: u8 = 19;
+    pub const ALLOC_FUNC_NEW: u8 = 20;
+    pub const ALLOC_DATA: u8 = 21;
+    pub const ALLOC_DATA_NEW: u8 = 22;
+    pub const RETURN: u8 = 23;
+    pub const CALL: u8 = 24;
+    pub const EXIT: u8 = 25;
+    pub const HALT: u8 = 26;
+    pub const GET_FUNC: u8 = 27;
+    pub const GET_DATA: u8 = 28;
+    pub const FREE_NODE: u8 = 29;
+}
+
 impl Insn {
-    pub fn push_byte_code(self, ret: &mut Vec<u8>) {
-        let op_code = match self {
-            Insn::None => 0,
-            Insn::Nil => 1,
-            Insn::Int(_) => 2,
-            Insn::Bool(_) => 3,
-            Insn::Add => 4,
-            Insn::Mul => 5,
-            Insn::Je8(_) => 6,
-            Insn::Je32(_) => 7,
-            Insn::J8(_) => 8,
-            Insn::J32(_) => 9,
-            Insn::GetLocal(_) => 10,
-            Insn::SetLocal(_) => 11,
-            Insn::AllocNode(_, _) => 12,
-            Insn::AllocNodeNew(_) => 13,
-            Insn::UpdateNode(_) => 14,
-            Insn::GetNode(_) => 15,
-            Insn::SetNode(_) => 16,
-            Insn::GetLast(_) => 17,
-            Insn::SaveLast => 18,
-            Insn::AllocFunc(_, _) => 19,
-            Insn::AllocFuncNew(_) => 20,
-            Insn::AllocData(_, _) => 21,
-            Insn::AllocDataNew(_) => 22,
-            Insn::Return => 23,
-            Insn::Call(_) => 24,
-            Insn::Exit => 25,
-            Insn::Halt => 26,
+    pub fn op_code(&self) -> u8 {
+        match self {
+            Insn::None => op::NONE,
+            Insn::Nil => op::NIL,
+            Insn::Int(_) => op::INT,
+            Insn::Bool(_) => op::BOOL,
+            Insn::Add => op::ADD,
+            Insn::Mul => op::MUL,
+            Insn::Je8(_) => op::JE8,
+            Insn::Je32(_) => op::JE32,
+            Insn::J8(_) => op::J8,
+            Insn::J32(_) => op::J32,
+            Insn::GetLocal(_) => op::GET_LOCAL,
+            Insn::SetLocal(_) => op::SET_LOCAL,
+            Insn::AllocNode(_, _) => op::ALLOC_NODE,
+            Insn::AllocNodeNew(_) => op::ALLOC_NODE_NEW,
+            Insn::UpdateNode(_) => op::UPDATE_NODE,
+            Insn::GetNode(_) => op::GET_NODE,
+            Insn::SetNode(_) => op::SET_NODE,
+            Insn::GetLast(_) => op::GET_LAST,
+            Insn::SaveLast => op::SAVE_LAST,
+            Insn::AllocFunc(_, _) => op::ALLOC_FUNC,
+            Insn::AllocFuncNew(_) => op::ALLOC_FUNC_NEW,
+            Insn::AllocData(_) => op::ALLOC_DATA,
+            Insn::AllocDataNew => op::ALLOC_DATA_NEW,
+            Insn::Return => op::RETURN,
+            Insn::Call(_) => op::CALL,
+            Insn::Exit => op::EXIT,
+            Insn::Halt => op::HALT,
+            Insn::GetFunc(_) => op::GET_FUNC,
+            Insn::GetData(_) => op::GET_DATA,
+            Insn::FreeNode(_) => op::FREE_NODE,
             Insn::Placeholder => panic!(),
-        };
+        }
+    }
+    pub fn push_byte_code(self, ret: &mut Vec<u8>) {
+        let op_code = self.op_code();
         ret.push(op_code);
         match self {
             // no immediate value
@@ -80,10 +123,15 @@ impl Insn {
             | Insn::Return
             | Insn::SaveLast
             | Insn::Exit
-            | Insn::Placeholder => return,
+            | Insn::AllocDataNew
+            | Insn::Placeholder => (),
             // i8
             Insn::Je8(i) | Insn::J8(i) => ret.push(i.to_le_bytes()[0]),
             Insn::Call(i)
+            | Insn::GetFunc(i)
+            | Insn::GetData(i)
+            | Insn::FreeNode(i)
+            | Insn::AllocData(i)
             | Insn::UpdateNode(i)
             | Insn::GetNode(i)
             | Insn::SetNode(i)
@@ -95,7 +143,7 @@ impl Insn {
             Insn::Int(i) | Insn::Je32(i) | Insn::J32(i) => push_int_le(i, ret),
 
             Insn::Bool(b) => ret.push(if b { 1 } else { 0 }),
-            Insn::AllocDataNew(insns) | Insn::AllocFuncNew(insns) | Insn::AllocNodeNew(insns) => {
+            Insn::AllocFuncNew(insns) | Insn::AllocNodeNew(insns) => {
                 let offset = ret.len();
                 for _ in 0..4 {
                     ret.push(0);
@@ -108,7 +156,7 @@ impl Insn {
                     ret[offset + i] = *v;
                 }
             }
-            Insn::AllocNode(i, insns) | Insn::AllocFunc(i, insns) | Insn::AllocData(i, insns) => {
+            Insn::AllocNode(i, insns) | Insn::AllocFunc(i, insns) => {
                 ret.push((i as i32).to_le_bytes()[0]);
                 let offset = ret.len();
                 for _ in 0..4 {
@@ -130,14 +178,14 @@ impl Insn {
         if i8::MIN as i32 <= i && i <= i8::MAX as i32 {
             Insn::J8(i as i8)
         } else {
-            Insn::J32(i as i32)
+            Insn::J32(i)
         }
     }
     pub fn je(i: i32) -> Self {
         if i8::MIN as i32 <= i && i <= i8::MAX as i32 {
             Insn::Je8(i as i8)
         } else {
-            Insn::Je32(i as i32)
+            Insn::Je32(i)
         }
     }
 }
@@ -159,10 +207,15 @@ pub fn bytecode_len(v: &[Insn]) -> usize {
             | Insn::Return
             | Insn::SaveLast
             | Insn::Exit
+            | Insn::AllocDataNew
             | Insn::Placeholder => 1,
             // i8
             Insn::Je8(_) | Insn::J8(_) => 2,
             Insn::Call(_)
+            | Insn::GetFunc(_)
+            | Insn::GetData(_)
+            | Insn::FreeNode(_)
+            | Insn::AllocData(_)
             | Insn::UpdateNode(_)
             | Insn::GetNode(_)
             | Insn::SetNode(_)
@@ -174,19 +227,199 @@ pub fn bytecode_len(v: &[Insn]) -> usize {
             Insn::Int(_) | Insn::Je32(_) | Insn::J32(_) => 5,
 
             Insn::Bool(_) => 2,
-            Insn::AllocDataNew(insns) | Insn::AllocFuncNew(insns) | Insn::AllocNodeNew(insns) => {
-                1 + bytecode_len(&insns[..])
-            }
-            Insn::AllocNode(_, insns) | Insn::AllocFunc(_, insns) | Insn::AllocData(_, insns) => {
-                2 + bytecode_len(&insns[..])
-            }
+            // opcode, (offset,) 4 byte length, body
+            Insn::AllocFuncNew(insns) | Insn::AllocNodeNew(insns) => 5 + bytecode_len(&insns[..]),
+            Insn::AllocNode(_, insns) | Insn::AllocFunc(_, insns) => 6 + bytecode_len(&insns[..]),
         }
     }
     ret
 }
+// the code of one section; see container.rs for the whole program
+pub fn encode_insns(insns: &[Insn]) -> Vec<u8> {
+    let mut ret = Vec::with_capacity(bytecode_len(insns));
+    for insn in insns {
+        insn.clone().push_byte_code(&mut ret);
+    }
+    ret
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeErr {
+    UnexpectedEof(usize),     // byte offset where more input was needed
+    InvalidOpcode(u8, usize), // opcode, byte offset
+    BadLength(i32, usize),    // negative body length, byte offset
+}
+impl std::fmt::Display for DecodeErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeErr::UnexpectedEof(at) => write!(f, "unexpected end of code at {at:#06x}"),
+            DecodeErr::InvalidOpcode(op, at) => write!(f, "invalid opcode {op} at {at:#06x}"),
+            DecodeErr::BadLength(len, at) => write!(f, "invalid length {len} at {at:#06x}"),
+        }
+    }
+}
+
+// inverse of encode_insns
+pub fn decode_insns(bytes: &[u8]) -> Result<Vec<Insn>, DecodeErr> {
+    Decoder { bytes, pos: 0 }.insns(bytes.len())
+}
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+impl Decoder<'_> {
+    fn byte(&mut self) -> Result<u8, DecodeErr> {
+        let b = *self
+            .bytes
+            .get(self.pos)
+            .ok_or(DecodeErr::UnexpectedEof(self.pos))?;
+        self.pos += 1;
+        Ok(b)
+    }
+    fn int(&mut self) -> Result<i32, DecodeErr> {
+        let b = self
+            .bytes
+            .get(self.pos..self.pos + 4)
+            .ok_or(DecodeErr::UnexpectedEof(self.pos))?;
+        self.pos += 4;
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    fn len(&mut self) -> Result<usize, DecodeErr> {
+        let at = self.pos;
+        let len = self.int()?;
+        usize::try_from(len).map_err(|_| DecodeErr::BadLength(len, at))
+    }
+    // decodes instructions until exactly len bytes have been consumed
+    fn insns(&mut self, len: usize) -> Result<Vec<Insn>, DecodeErr> {
+        let end = self.pos + len;
+        if end > self.bytes.len() {
+            return Err(DecodeErr::UnexpectedEof(self.bytes.len()));
+        }
+        let outer = self.bytes;
+        self.bytes = &outer[..end];
+        let mut ret = vec![];
+        while self.pos < end {
+            ret.push(self.insn()?);
+        }
+        self.bytes = outer;
+        Ok(ret)
+    }
+    fn insn(&mut self) -> Result<Insn, DecodeErr> {
+        let at = self.pos;
+        let insn = match self.byte()? {
+            op::NONE => Insn::None,
+            op::NIL => Insn::Nil,
+            op::INT => Insn::Int(self.int()?),
+            op::BOOL => Insn::Bool(self.byte()? != 0),
+            op::ADD => Insn::Add,
+            op::MUL => Insn::Mul,
+            op::JE8 => Insn::Je8(self.byte()? as i8),
+            op::JE32 => Insn::Je32(self.int()?),
+            op::J8 => Insn::J8(self.byte()? as i8),
+            op::J32 => Insn::J32(self.int()?),
+            op::GET_LOCAL => Insn::GetLocal(self.byte()? as usize),
+            op::SET_LOCAL => Insn::SetLocal(self.byte()? as usize),
+            op::ALLOC_NODE => {
+                let i = self.byte()? as usize;
+                let len = self.len()?;
+                Insn::AllocNode(i, self.insns(len)?)
+            }
+            op::ALLOC_NODE_NEW => {
+                let len = self.len()?;
+                Insn::AllocNodeNew(self.insns(len)?)
+            }
+            op::UPDATE_NODE => Insn::UpdateNode(self.byte()? as usize),
+            op::GET_NODE => Insn::GetNode(self.byte()? as usize),
+            op::SET_NODE => Insn::SetNode(self.byte()? as usize),
+            op::GET_LAST => Insn::GetLast(self.byte()? as usize),
+            op::SAVE_LAST => Insn::SaveLast,
+            op::ALLOC_FUNC => {
+                let i = self.byte()? as usize;
+                let len = self.len()?;
+                Insn::AllocFunc(i, self.insns(len)?)
+            }
+            op::ALLOC_FUNC_NEW => {
+                let len = self.len()?;
+                Insn::AllocFuncNew(self.insns(len)?)
+            }
+            op::ALLOC_DATA => Insn::AllocData(self.byte()? as usize),
+            op::ALLOC_DATA_NEW => Insn::AllocDataNew,
+            op::RETURN => Insn::Return,
+            op::CALL => Insn::Call(self.byte()? as usize),
+            op::EXIT => Insn::Exit,
+            op::HALT => Insn::Halt,
+            op::GET_FUNC => Insn::GetFunc(self.byte()? as usize),
+            op::GET_DATA => Insn::GetData(self.byte()? as usize),
+            op::FREE_NODE => Insn::FreeNode(self.byte()? as usize),
+            op => return Err(DecodeErr::InvalidOpcode(op, at)),
+        };
+        Ok(insn)
+    }
+}
+
 #[test]
 fn insn_8_32() {
     assert_eq!(Insn::j(1000), Insn::J32(1000));
     assert_eq!(Insn::j(100), Insn::J8(100));
     assert_eq!(12, 12i32.to_le_bytes()[0]);
 }
+#[test]
+fn decode_encode_roundtrip() {
+    // xorshift, so that failures are reproducible
+    let mut seed = 0x2545f491u32;
+    let mut rand = move |n: u32| {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed % n
+    };
+    fn gen(rand: &mut impl FnMut(u32) -> u32, depth: u32) -> Vec<Insn> {
+        let n = rand(8);
+        (0..n)
+            .map(|_| {
+                let i = rand(256) as usize;
+                let x = rand(u32::MAX) as i32;
+                match rand(if depth > 0 { 30 } else { 26 }) {
+                    0 => Insn::None,
+                    1 => Insn::Nil,
+                    2 => Insn::Int(x),
+                    3 => Insn::Bool(x % 2 == 0),
+                    4 => Insn::Add,
+                    5 => Insn::Mul,
+                    6 => Insn::Je8(x as i8),
+                    7 => Insn::Je32(x),
+                    8 => Insn::J8(x as i8),
+                    9 => Insn::J32(x),
+                    10 => Insn::GetLocal(i),
+                    11 => Insn::SetLocal(i),
+                    12 => Insn::UpdateNode(i),
+                    13 => Insn::GetNode(i),
+                    14 => Insn::SetNode(i),
+                    15 => Insn::GetLast(i),
+                    16 => Insn::SaveLast,
+                    17 => Insn::AllocData(i),
+                    18 => Insn::AllocDataNew,
+                    19 => Insn::Return,
+                    20 => Insn::Call(i),
+                    21 => Insn::Exit,
+                    22 => Insn::Halt,
+                    23 => Insn::GetFunc(i),
+                    24 => Insn::GetData(i),
+                    25 => Insn::FreeNode(i),
+                    26 => Insn::AllocNode(i, gen(rand, depth - 1)),
+                    27 => Insn::AllocNodeNew(gen(rand, depth - 1)),
+                    28 => Insn::AllocFunc(i, gen(rand, depth - 1)),
+                    _ => Insn::AllocFuncNew(gen(rand, depth - 1)),
+                }
+            })
+            .collect()
+    }
+    for _ in 0..1000 {
+        let insns = gen(&mut rand, 2);
+        let bytes = encode_insns(&insns);
+        assert_eq!(bytes.len(), bytecode_len(&insns));
+        assert_eq!(decode_insns(&bytes), Ok(insns));
+    }
+    assert_eq!(decode_insns(&[1, 30]), Err(DecodeErr::InvalidOpcode(30, 1)));
+    assert_eq!(decode_insns(&[1, 2, 1]), Err(DecodeErr::UnexpectedEof(2)));
+}
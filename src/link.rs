@@ -0,0 +1,433 @@
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::container::crc32;
+use crate::telemetry::Sample;
+
+// framing of the serial line (integers are little endian)
+//
+//   0xA5, kind: u8, seq: u8, len: u16, payload: [u8; len], CRC32 of kind..payload: u32
+//
+// the host sends Begin (payload: total length as u32) and then the program in
+// Data frames. each of them is answered by Ack or Nak with the same seq and
+// retransmitted on Nak or timeout. once the whole program has arrived the device
+// answers with Result (payload: load result, then a message). a Begin of more than
+// MAX_UPLOAD bytes, or Data without a Begin or past its length, is answered by a
+// Result with an invalid length. Reset (no payload)
+// drops every node, function and data on the device and is answered by Result too
+//
+// after every update cycle the device sends Telemetry, which is not acked
+// (see telemetry.rs for the payload)
+pub const SYNC: u8 = 0xA5;
+pub const MAX_PAYLOAD: usize = 256;
+pub const CHUNK_SIZE: usize = 64;
+pub const MAX_UPLOAD: usize = 16384; // MAX_UPLOAD in emfrp.c
+const MAX_RETRIES: usize = 5;
+const ACK_TIMEOUT: Duration = Duration::from_millis(500);
+const RESULT_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameKind {
+    Begin = 1,
+    Data = 2,
+    Ack = 3,
+    Nak = 4,
+    Result = 5,
+    Telemetry = 6,
+    Reset = 7,
+}
+impl FrameKind {
+    fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(FrameKind::Begin),
+            2 => Some(FrameKind::Data),
+            3 => Some(FrameKind::Ack),
+            4 => Some(FrameKind::Nak),
+            5 => Some(FrameKind::Result),
+            6 => Some(FrameKind::Telemetry),
+            7 => Some(FrameKind::Reset),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub kind: FrameKind,
+    pub seq: u8,
+    pub payload: Vec<u8>,
+}
+impl Frame {
+    pub fn new(kind: FrameKind, seq: u8, payload: Vec<u8>) -> Self {
+        assert!(payload.len() <= MAX_PAYLOAD);
+        Self { kind, seq, payload }
+    }
+    pub fn encode(&self) -> Vec<u8> {
+        let mut ret = vec![SYNC, self.kind as u8, self.seq];
+        ret.extend((self.payload.len() as u16).to_le_bytes());
+        ret.extend(&self.payload);
+        let crc = crc32(&ret[1..]);
+        ret.extend(crc.to_le_bytes());
+        ret
+    }
+}
+
+// reassembles frames from a byte stream, skipping anything before SYNC
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buf: Vec<u8>,
+}
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // returns Err(seq) for a frame that arrived damaged
+    pub fn push(&mut self, b: u8) -> Option<Result<Frame, u8>> {
+        if self.buf.is_empty() && b != SYNC {
+            return None;
+        }
+        self.buf.push(b);
+        if self.buf.len() < 5 {
+            return None;
+        }
+        let len = u16::from_le_bytes([self.buf[3], self.buf[4]]) as usize;
+        if len > MAX_PAYLOAD {
+            // not a header after all
+            self.buf.clear();
+            return None;
+        }
+        if self.buf.len() < 5 + len + 4 {
+            return None;
+        }
+        let buf = std::mem::take(&mut self.buf);
+        let seq = buf[2];
+        let crc = u32::from_le_bytes([buf[5 + len], buf[6 + len], buf[7 + len], buf[8 + len]]);
+        match FrameKind::from_u8(buf[1]) {
+            Some(kind) if crc32(&buf[1..5 + len]) == crc => Some(Ok(Frame {
+                kind,
+                seq,
+                payload: buf[5..5 + len].to_vec(),
+            })),
+            _ => Some(Err(seq)),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum LinkErr {
+    Io(std::io::Error),
+    NoAck(u8), // seq of the frame given up on
+    NoResult,
+}
+impl std::fmt::Display for LinkErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LinkErr::Io(e) => write!(f, "{e}"),
+            LinkErr::NoAck(seq) => {
+                write!(
+                    f,
+                    "frame {seq} was not acknowledged after {MAX_RETRIES} tries"
+                )
+            }
+            LinkErr::NoResult => write!(f, "the device did not answer"),
+        }
+    }
+}
+impl From<std::io::Error> for LinkErr {
+    fn from(e: std::io::Error) -> Self {
+        LinkErr::Io(e)
+    }
+}
+
+// the answer of the device to an upload; code follows load_result_t in emfrp.c
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceResult {
+    pub code: u8,
+    pub msg: String,
+}
+impl std::fmt::Display for DeviceResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let s = match self.code {
+            0 => "ok",
+            1 => "not an emfrp program",
+            2 => "unsupported version",
+            3 => "invalid length",
+            4 => "checksum mismatch",
+            _ => "error",
+        };
+        if self.msg.is_empty() {
+            write!(f, "{s}")
+        } else {
+            write!(f, "{s}: {}", self.msg)
+        }
+    }
+}
+
+// the host side. reads on port must time out (e.g. SerialPort::set_read_timeout)
+pub struct Link<T> {
+    port: T,
+    seq: u8,
+    decoder: FrameDecoder,
+    pending: VecDeque<u8>, // read but not decoded yet
+    samples: VecDeque<Sample>,
+}
+impl<T: Read + Write> Link<T> {
+    pub fn new(port: T) -> Self {
+        Self {
+            port,
+            seq: 0,
+            decoder: FrameDecoder::new(),
+            pending: VecDeque::new(),
+            samples: VecDeque::new(),
+        }
+    }
+    pub fn upload(&mut self, code: &[u8]) -> Result<DeviceResult, LinkErr> {
+        self.send(FrameKind::Begin, (code.len() as u32).to_le_bytes().to_vec())?;
+        for chunk in code.chunks(CHUNK_SIZE) {
+            self.send(FrameKind::Data, chunk.to_vec())?;
+        }
+        self.result()
+    }
+    pub fn reset(&mut self) -> Result<DeviceResult, LinkErr> {
+        self.send(FrameKind::Reset, vec![])?;
+        self.result()
+    }
+    fn result(&mut self) -> Result<DeviceResult, LinkErr> {
+        let deadline = Instant::now() + RESULT_TIMEOUT;
+        while let Some(frame) = self.recv(deadline)? {
+            if frame.kind == FrameKind::Result && !frame.payload.is_empty() {
+                // telemetry sent before the result is about the previous program
+                self.samples.clear();
+                return Ok(DeviceResult {
+                    code: frame.payload[0],
+                    msg: String::from_utf8_lossy(&frame.payload[1..]).into_owned(),
+                });
+            }
+        }
+        Err(LinkErr::NoResult)
+    }
+    // the next telemetry sample, waiting at most timeout for it. with a zero
+    // timeout the port is still read once
+    pub fn telemetry(&mut self, timeout: Duration) -> Result<Option<Sample>, LinkErr> {
+        let deadline = Instant::now() + timeout;
+        while self.samples.is_empty() && self.recv(deadline)?.is_some() {}
+        Ok(self.samples.pop_front())
+    }
+    fn send(&mut self, kind: FrameKind, payload: Vec<u8>) -> Result<(), LinkErr> {
+        let seq = self.seq;
+        self.seq = self.seq.wrapping_add(1);
+        let bytes = Frame::new(kind, seq, payload).encode();
+        for _ in 0..MAX_RETRIES {
+            self.port.write_all(&bytes)?;
+            self.port.flush()?;
+            let deadline = Instant::now() + ACK_TIMEOUT;
+            while let Some(frame) = self.recv(deadline)? {
+                match frame.kind {
+                    FrameKind::Ack if frame.seq == seq => return Ok(()),
+                    FrameKind::Nak if frame.seq == seq => break,
+                    // late answers to earlier frames
+                    _ => continue,
+                }
+            }
+        }
+        Err(LinkErr::NoAck(seq))
+    }
+    // None on timeout. telemetry is also queued for Link::telemetry
+    fn recv(&mut self, deadline: Instant) -> Result<Option<Frame>, LinkErr> {
+        loop {
+            while let Some(b) = self.pending.pop_front() {
+                // damaged frames are dropped and end in a timeout
+                if let Some(Ok(frame)) = self.decoder.push(b) {
+                    if frame.kind == FrameKind::Telemetry {
+                        self.samples.extend(Sample::decode(&frame.payload));
+                    }
+                    return Ok(Some(frame));
+                }
+            }
+            let mut buf = [0; 64];
+            match self.port.read(&mut buf) {
+                Ok(n) => self.pending.extend(&buf[..n]),
+                Err(e) if matches!(e.kind(), ErrorKind::TimedOut | ErrorKind::WouldBlock) => (),
+                Err(e) => return Err(e.into()),
+            }
+            if self.pending.is_empty() && Instant::now() >= deadline {
+                return Ok(None);
+            }
+        }
+    }
+}
+
+// what the host asked the device to do
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Load(Vec<u8>), // a complete program
+    Reset,
+}
+
+// the device side, fed with the bytes received from the host
+#[derive(Debug, Default)]
+pub struct DeviceLink {
+    decoder: FrameDecoder,
+    last_seq: Option<u8>,
+    expected: Option<usize>, // length announced by Begin while a program arrives
+    code: Vec<u8>,
+}
+impl DeviceLink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // returns the bytes to send back and what the host asked for, if anything.
+    // the request has to be answered by result
+    pub fn input(&mut self, bytes: &[u8]) -> (Vec<u8>, Option<Request>) {
+        let mut out = vec![];
+        let mut req = None;
+        for b in bytes {
+            match self.decoder.push(*b) {
+                None => (),
+                Some(Err(seq)) => out.extend(Frame::new(FrameKind::Nak, seq, vec![]).encode()),
+                Some(Ok(frame)) => {
+                    out.extend(Frame::new(FrameKind::Ack, frame.seq, vec![]).encode());
+                    if self.last_seq == Some(frame.seq) {
+                        // our ack was lost
+                        continue;
+                    }
+                    self.last_seq = Some(frame.seq);
+                    match frame.kind {
+                        FrameKind::Begin => {
+                            let len = <[u8; 4]>::try_from(&frame.payload[..])
+                                .map(|p| u32::from_le_bytes(p) as usize);
+                            self.code.clear();
+                            self.expected = match len {
+                                Ok(n) if n > 0 && n <= MAX_UPLOAD => Some(n),
+                                _ => {
+                                    out.extend(self.result(3, "invalid program length"));
+                                    None
+                                }
+                            };
+                        }
+                        FrameKind::Data => match self.expected {
+                            Some(n) if self.code.len() + frame.payload.len() <= n => {
+                                self.code.extend(&frame.payload);
+                                if self.code.len() == n {
+                                    self.expected = None;
+                                    req = Some(Request::Load(std::mem::take(&mut self.code)));
+                                }
+                            }
+                            _ => {
+                                self.expected = None;
+                                self.code.clear();
+                                out.extend(self.result(3, "data outside of an upload"));
+                            }
+                        },
+                        FrameKind::Reset => req = Some(Request::Reset),
+                        _ => (),
+                    }
+                }
+            }
+        }
+        (out, req)
+    }
+    pub fn result(&mut self, code: u8, msg: &str) -> Vec<u8> {
+        let mut payload = vec![code];
+        payload.extend(msg.as_bytes().iter().take(MAX_PAYLOAD - 1));
+        Frame::new(FrameKind::Result, 0, payload).encode()
+    }
+}
+
+#[test]
+fn link_test() {
+    // a device behind a line that damages the first copy of every third frame
+    struct Line {
+        device: DeviceLink,
+        to_host: VecDeque<u8>,
+        sent: usize,
+        received: Vec<u8>,
+    }
+    impl Write for Line {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut buf = buf.to_vec();
+            self.sent += 1;
+            if self.sent.is_multiple_of(3) {
+                let last = buf.len() - 1;
+                buf[last] ^= 0xff;
+            }
+            let (out, req) = self.device.input(&buf);
+            self.to_host.extend(out);
+            match req {
+                Some(Request::Load(p)) => {
+                    self.to_host.extend(self.device.result(0, "3 nodes"));
+                    self.received = p;
+                }
+                Some(Request::Reset) => {
+                    self.to_host.extend(self.device.result(0, ""));
+                    self.received.clear();
+                }
+                None => (),
+            }
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl Read for Line {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.to_host.is_empty() {
+                return Err(ErrorKind::TimedOut.into());
+            }
+            let n = buf.len().min(self.to_host.len());
+            for b in buf.iter_mut().take(n) {
+                *b = self.to_host.pop_front().unwrap();
+            }
+            Ok(n)
+        }
+    }
+    let code: Vec<u8> = (0..200).map(|i| i as u8).collect();
+    let mut link = Link::new(Line {
+        device: DeviceLink::new(),
+        to_host: VecDeque::new(),
+        sent: 0,
+        received: vec![],
+    });
+    let res = link.upload(&code).unwrap();
+    assert_eq!(res.code, 0);
+    assert_eq!(res.to_string(), "ok: 3 nodes");
+    assert_eq!(link.port.received, code);
+    assert!(link.port.sent > 5); // Begin + 4 chunks, plus retransmissions
+    assert_eq!(link.reset().unwrap().to_string(), "ok");
+    assert!(link.port.received.is_empty());
+}
+
+#[test]
+fn device_link_test() {
+    let mut device = DeviceLink::new();
+    // the load results answered to some frames, and the program if one arrived
+    let mut send = |frames: &[Frame]| {
+        let bytes: Vec<u8> = frames.iter().flat_map(Frame::encode).collect();
+        let (out, req) = device.input(&bytes);
+        let mut decoder = FrameDecoder::new();
+        let results: Vec<u8> = out
+            .iter()
+            .filter_map(|b| decoder.push(*b)?.ok())
+            .filter(|f| f.kind == FrameKind::Result)
+            .map(|f| f.payload[0])
+            .collect();
+        (results, req)
+    };
+    let begin = |seq, len: u32| Frame::new(FrameKind::Begin, seq, len.to_le_bytes().to_vec());
+    let data = |seq, len| Frame::new(FrameKind::Data, seq, vec![7; len]);
+    assert_eq!(send(&[data(1, 8)]), (vec![3], None));
+    assert_eq!(
+        send(&[Frame::new(FrameKind::Begin, 2, vec![8, 0])]),
+        (vec![3], None)
+    );
+    assert_eq!(send(&[begin(3, MAX_UPLOAD as u32 + 1)]), (vec![3], None));
+    assert_eq!(send(&[begin(4, 4), data(5, 8)]), (vec![3], None));
+    assert_eq!(
+        send(&[begin(6, 12), data(7, 8), data(8, 4)]),
+        (vec![], Some(Request::Load(vec![7; 12])))
+    );
+    // the program is complete, so more data is out of place
+    assert_eq!(send(&[data(9, 4)]), (vec![3], None));
+}
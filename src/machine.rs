@@ -8,25 +8,114 @@ use std::{
 };
 
 use crate::{
-    compile::RuntimeNodeIndex, insn::*, DEBUG, MACHINE_FILE, MAX_NUMBER_OF_NODE, UPD_FREQUENCY_MS,
+    container::*, driver::*, insn::*, telemetry::Sample, DEBUG, MACHINE_FILE, MAX_NUMBER_OF_NODE,
+    STACK_SIZE, UPD_FREQUENCY_MS,
 };
 use std::fmt::Debug;
-use std::fs::OpenOptions;
-use std::{io::Write, sync::mpsc::Sender, time};
+use std::fs::{File, OpenOptions};
+use std::{io::Write, sync::mpsc::Sender};
 // TODO: stack size
 // TODO: Value of Stack
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     Int(i32),
     Bool(bool),
     Nil,
-    Insn(*const Insn),
-    Usize(usize),
+    Ret(Pc),      // return address
+    Usize(usize), // saved rbp
+    Func(FuncOffset),
+}
+impl std::fmt::Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Int(i) => write!(f, "{i}"),
+            Value::Bool(b) => write!(f, "{b}"),
+            Value::Nil => write!(f, "nil"),
+            v => write!(f, "{:?}", v),
+        }
+    }
 }
-unsafe impl Send for Value {}
 #[derive(Debug)]
 pub enum RuntimeErr {
     StackOverflow,
+    StackUnderflow,
+    StackNotEmpty,            // halt or exit left values behind
+    InvalidOpcode(u8, usize), // opcode, byte offset
+    UnexpectedEnd(usize),     // byte offset where more code was needed
+    TypeMismatch(&'static str, Value),
+    NoLocal(usize),
+    NoNode(usize),
+    NoFunc(usize),
+    NoData(usize),
+    BadReturn, // the frame is not one made by call or updatenode
+}
+impl std::fmt::Display for RuntimeErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuntimeErr::StackOverflow => write!(f, "stack overflow"),
+            RuntimeErr::StackUnderflow => write!(f, "stack underflow"),
+            RuntimeErr::StackNotEmpty => write!(f, "values left on the stack"),
+            RuntimeErr::InvalidOpcode(op, at) => write!(f, "invalid opcode {op} at {at:#06x}"),
+            RuntimeErr::UnexpectedEnd(at) => write!(f, "unexpected end of code at {at:#06x}"),
+            RuntimeErr::TypeMismatch(ty, v) => write!(f, "expected {ty} but found {v}"),
+            RuntimeErr::NoLocal(i) => write!(f, "no local {i}"),
+            RuntimeErr::NoNode(i) => write!(f, "no node {i}"),
+            RuntimeErr::NoFunc(i) => write!(f, "no function {i}"),
+            RuntimeErr::NoData(i) => write!(f, "no data {i}"),
+            RuntimeErr::BadReturn => write!(f, "return without a call"),
+        }
+    }
+}
+
+// a position in encoded code. the code is shared with the node or function
+// it belongs to, so a return address stays valid even if that is redefined
+#[derive(Clone, PartialEq)]
+pub struct Pc {
+    code: Arc<[u8]>,
+    at: usize,
+}
+impl Debug for Pc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:#06x}", self.at)
+    }
+}
+impl Pc {
+    fn byte(&mut self) -> Result<u8, RuntimeErr> {
+        let b = *self
+            .code
+            .get(self.at)
+            .ok_or(RuntimeErr::UnexpectedEnd(self.at))?;
+        self.at += 1;
+        Ok(b)
+    }
+    fn int(&mut self) -> Result<i32, RuntimeErr> {
+        let b = self
+            .code
+            .get(self.at..self.at + 4)
+            .ok_or(RuntimeErr::UnexpectedEnd(self.at))?;
+        self.at += 4;
+        Ok(i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+    // the length prefixed body of an alloc instruction, copied as the device does
+    fn body(&mut self) -> Result<Arc<[u8]>, RuntimeErr> {
+        let len = self.int()?;
+        let end = usize::try_from(len)
+            .ok()
+            .map(|len| self.at + len)
+            .filter(|end| *end <= self.code.len())
+            .ok_or(RuntimeErr::UnexpectedEnd(self.at))?;
+        let body = Arc::from(&self.code[self.at..end]);
+        self.at = end;
+        Ok(body)
+    }
+    // offsets are in bytes from the end of the jump instruction
+    fn jump(&mut self, offset: i32) -> Result<(), RuntimeErr> {
+        self.at = self
+            .at
+            .checked_add_signed(offset as isize)
+            .ok_or(RuntimeErr::UnexpectedEnd(self.at))?;
+        Ok(())
+    }
 }
 
 #[derive(Debug)]
@@ -34,19 +123,58 @@ pub struct Msg {
     code: Arc<Mutex<Option<Code>>>,
     code_is_updated: Arc<Mutex<bool>>,
 }
-unsafe impl Send for Msg {}
-unsafe impl Sync for Msg {}
 pub enum Code {
     DefNode { init: Vec<Insn>, upd: Vec<Insn> },
     Exp(Vec<Insn>),
+    Image(Vec<u8>), // as uploaded to the device
 }
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 enum InputAction {
-    Device(fn() -> Value),
-    Insn(Vec<Insn>),
+    Device(usize), // index into Machine::inputs
+    Insn(Arc<[u8]>),
     None,
 }
-type OutputAction = Option<fn(&Value)>;
+type OutputAction = Option<usize>; // index into Machine::outputs
+
+// when the driver of an input node is read next; between readings the node
+// keeps its value
+#[derive(Debug, Clone, Copy, Default)]
+struct Sampling {
+    every_ms: u64, // 0 for every cycle
+    next_ms: u64,
+}
+impl Sampling {
+    fn due(&mut self, now_ms: u64) -> bool {
+        if self.every_ms == 0 {
+            return true;
+        }
+        if now_ms < self.next_ms {
+            return false;
+        }
+        // see SECTION_DRIVERS in container.rs
+        while self.next_ms <= now_ms {
+            self.next_ms += self.every_ms;
+        }
+        true
+    }
+}
+// the state that init changes, put back when a load fails
+struct Saved {
+    node_v: Vec<Value>,
+    node_v_last: Vec<Value>,
+    node_input_action: Vec<InputAction>,
+    node_output_action: Vec<OutputAction>,
+    nodes: usize,
+    funcs: Vec<Arc<[u8]>>,
+    datas: Vec<Value>,
+}
+// with on-change updates, whether the node is updated in this cycle
+#[derive(Debug, Clone, Default)]
+struct Deps {
+    always: bool,      // reads a driver or a value @last
+    users: Vec<usize>, // updated again when this node changes
+    dirty: bool,
+}
 
 pub struct Machine {
     stack: Vec<Value>,
@@ -54,8 +182,23 @@ pub struct Machine {
     node_v_last: Vec<Value>,
     node_input_action: Vec<InputAction>,
     node_output_action: Vec<OutputAction>,
+    node_sampling: Vec<Sampling>,
+    node_deps: Vec<Deps>,
+    on_change: bool, // the program came with dependencies
+    nodes: usize,    // slots taken by AllocNodeNew so far
     out: Sender<String>,
-    update: Vec<Insn>,
+    update: Arc<[u8]>,
+    cycle: u32,                      // number of update cycles run
+    clock_ms: u64,                   // virtual time, advanced by step
+    period_ms: u64,                  // virtual time between update cycles
+    reported: Vec<Option<Value>>,    // node values as of the last telemetry
+    samples: Option<Sender<Sample>>, // telemetry of the cycles run by Machine::run
+    funcs: Vec<Arc<[u8]>>,
+    datas: Vec<Value>,
+    trace: bool, // dump the state to MACHINE_FILE after every instruction
+    trace_file: Option<File>,
+    inputs: Vec<(String, Box<dyn InputDriver>)>, // by name
+    outputs: Vec<(String, Box<dyn OutputDriver>)>,
 }
 impl Debug for Machine {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -92,12 +235,36 @@ impl Debug for Code {
                     ret.push_str(&format!(" {:?}\n", insn));
                 }
             }
+            Code::Image(bytes) => ret.push_str(&format!(" {:?}\n", bytes)),
         }
 
         write!(f, "{}", ret)
     }
 }
 
+// a machine started by Machine::spawn; it stops when this is dropped
+pub struct LocalMachine {
+    msg: Msg,
+    out: Receiver<String>,
+    samples: Receiver<Sample>,
+}
+impl LocalMachine {
+    // hands the code over and waits for the answer of the machine
+    pub fn load(&self, mut code: Code) -> String {
+        while let Some(c) = self.msg.send_code(code) {
+            code = c;
+            thread::sleep(Duration::from_millis(1));
+        }
+        self.out
+            .recv()
+            .unwrap_or_else(|_| "the machine has stopped".to_string())
+    }
+    // telemetry of the cycles run since the last call
+    pub fn samples(&self) -> Vec<Sample> {
+        self.samples.try_iter().collect()
+    }
+}
+
 impl Msg {
     // If send_code returns None, upd = true
     // If upd = true, try_receive_code returns Some(code) and upd turns to false
@@ -135,60 +302,74 @@ impl Msg {
         }
     }
 }
-pub fn write_file_append(s: String) {
-    OpenOptions::new()
-        .create(true)
-        .append(true)
-        .open(MACHINE_FILE)
-        .unwrap()
-        .write_all(s.as_bytes())
-        .unwrap();
-}
-fn timer() -> Arc<Mutex<bool>> {
-    let timer = Arc::new(Mutex::new(false));
-    let clone = timer.clone();
-    thread::spawn(move || loop {
-        thread::sleep(Duration::from_millis(UPD_FREQUENCY_MS));
-        *timer.lock().unwrap() = true;
-    });
-    clone
-}
-fn check_if_true(mtx: &Arc<Mutex<bool>>) -> bool {
-    let mut guard = mtx.lock().unwrap();
-    if *guard {
-        *guard = false;
-        true
-    } else {
-        false
-    }
-}
 
 impl Machine {
-    pub fn add_input_node(&mut self, ind: RuntimeNodeIndex, f: fn() -> Value) {
-        let i = ind.i();
-        self.node_input_action[i] = InputAction::Device(f);
-        self.update.push(Insn::UpdateNode(i));
-        self.update.push(Insn::SetNode(i));
-    }
-    pub fn add_output_node(&mut self, ind: RuntimeNodeIndex, f: fn(&Value)) {
-        let i = ind.i();
-        self.node_output_action[i] = Some(f);
-        self.update.push(Insn::UpdateNode(i));
-        self.update.push(Insn::SetNode(i));
+    // drivers are attached by name to the I/O nodes of every program loaded later
+    pub fn register_input(&mut self, name: &str, driver: impl InputDriver + 'static) {
+        register(&mut self.inputs, name, Box::new(driver))
+    }
+    pub fn register_output(&mut self, name: &str, driver: impl OutputDriver + 'static) {
+        register(&mut self.outputs, name, Box::new(driver))
+    }
+    // returns the names that have no driver
+    fn attach_drivers(&mut self, drivers: &[DriverEntry]) -> Vec<String> {
+        let mut missing = vec![];
+        for d in drivers {
+            match d.kind {
+                DriverKind::Input => match self.inputs.iter().position(|(n, _)| *n == d.name) {
+                    Some(j) => {
+                        self.node_input_action[d.node] = InputAction::Device(j);
+                        // read on the next cycle
+                        self.node_sampling[d.node] = Sampling {
+                            every_ms: d.every_ms as u64,
+                            next_ms: self.clock_ms,
+                        };
+                    }
+                    None => missing.push(d.name.clone()),
+                },
+                DriverKind::Output => match self.outputs.iter().position(|(n, _)| *n == d.name) {
+                    Some(j) => self.node_output_action[d.node] = Some(j),
+                    None => missing.push(d.name.clone()),
+                },
+            }
+        }
+        missing
     }
     fn make_empty_node(&mut self) {
         self.node_v.push(Value::Nil);
         self.node_v_last.push(Value::Nil);
         self.node_input_action.push(InputAction::None);
         self.node_output_action.push(None);
+        self.node_sampling.push(Sampling::default());
+        self.node_deps.push(Deps::default());
+    }
+    // a node that the program does not have fails the load
+    fn check_deps(&self, deps: &[DepEntry]) -> Result<(), RuntimeErr> {
+        for d in deps {
+            if let Some(i) = std::iter::once(&d.node)
+                .chain(&d.users)
+                .find(|i| **i >= self.nodes)
+            {
+                return Err(RuntimeErr::NoNode(*i));
+            }
+        }
+        Ok(())
+    }
+    // see SECTION_DEPS in container.rs
+    fn set_deps(&mut self, deps: &[DepEntry]) {
+        for d in &mut self.node_deps {
+            *d = Deps {
+                dirty: true,
+                ..Deps::default()
+            };
+        }
+        for d in deps {
+            self.node_deps[d.node].always = d.always;
+            self.node_deps[d.node].users.clone_from(&d.users);
+        }
+        self.on_change = !deps.is_empty();
     }
     pub fn new() -> (Self, Receiver<String>) {
-        OpenOptions::new()
-            .create(true)
-            .write(true)
-            .truncate(true)
-            .open(MACHINE_FILE)
-            .unwrap();
         let (sender, receiver) = mpsc::channel();
         let node_v = Vec::with_capacity(MAX_NUMBER_OF_NODE);
         let node_v_last = Vec::with_capacity(MAX_NUMBER_OF_NODE);
@@ -199,10 +380,25 @@ impl Machine {
             stack: vec![],
             node_v,
             node_v_last,
+            nodes: 0,
             out: sender,
-            update: vec![],
+            update: Arc::from([]),
+            cycle: 0,
+            clock_ms: 0,
+            period_ms: UPD_FREQUENCY_MS,
+            reported: vec![],
+            samples: None,
+            funcs: vec![],
+            datas: vec![],
+            trace: DEBUG,
+            trace_file: None,
+            inputs: vec![],
+            outputs: vec![],
             node_input_action,
             node_output_action,
+            node_sampling: Vec::with_capacity(MAX_NUMBER_OF_NODE),
+            node_deps: Vec::with_capacity(MAX_NUMBER_OF_NODE),
+            on_change: false,
         };
         for _ in 0..MAX_NUMBER_OF_NODE {
             machine.make_empty_node();
@@ -210,24 +406,88 @@ impl Machine {
 
         (machine, receiver)
     }
+    pub fn set_trace(&mut self, trace: bool) {
+        self.trace = trace;
+    }
+    // MACHINE_FILE is emptied by the first line written by the machine, so
+    // that it is not touched at all while tracing is off
+    fn write_trace(&mut self, s: String) {
+        if !self.trace {
+            return;
+        }
+        let file = self.trace_file.get_or_insert_with(|| {
+            OpenOptions::new()
+                .create(true)
+                .write(true)
+                .truncate(true)
+                .open(MACHINE_FILE)
+                .unwrap()
+        });
+        file.write_all(s.as_bytes()).unwrap();
+    }
+    // values of the nodes that are alive, by index
+    pub fn node_values(&self) -> Vec<(usize, Value)> {
+        (0..self.nodes)
+            .filter(|i| !matches!(self.node_input_action[*i], InputAction::None))
+            .map(|i| (i, self.node_v[i].clone()))
+            .collect()
+    }
+    // the nodes that changed since the last call, to be sent after an update cycle
+    pub fn telemetry(&mut self) -> Sample {
+        let mut changed = vec![];
+        for (i, v) in self.node_values() {
+            if self.reported.len() <= i {
+                self.reported.resize(i + 1, None);
+            }
+            if self.reported[i].as_ref() != Some(&v) {
+                self.reported[i] = Some(v.clone());
+                changed.push((i, v));
+            }
+        }
+        Sample {
+            cycle: self.cycle,
+            time_ms: self.clock_ms as u32,
+            changed,
+        }
+    }
+    // starts the machine on its own thread, for the REPL to use instead of a board
+    pub fn spawn() -> LocalMachine {
+        let (mut machine, out) = Machine::new();
+        let (tx, samples) = mpsc::channel();
+        machine.samples = Some(tx);
+        LocalMachine {
+            msg: machine.run(),
+            out,
+            samples,
+        }
+    }
+    // the real-time front-end: steps the machine once every period of wall
+    // clock time on its own thread, and takes the code sent through Msg
     pub fn run(mut self) -> Msg {
         let code = Arc::new(Mutex::new(None));
         let code_is_updated = Arc::new(Mutex::new(false));
-        let timer = timer();
         let code_clone = code.clone();
         let upd_clone = code_is_updated.clone();
         thread::spawn(move || {
             let code_mtx = code;
-            loop {
-                if check_if_true(&timer) {
-                    if let Err(e) = self.exec_upd() {
-                        write_file_append(format!("Update Error : {:?}", e))
+            let mut next = Instant::now() + Duration::from_millis(self.period_ms);
+            // the machine stops once Msg is dropped
+            while Arc::strong_count(&code_mtx) > 1 {
+                if Instant::now() >= next {
+                    next += Duration::from_millis(self.period_ms);
+                    if let Err(e) = self.step(1) {
+                        self.write_trace(format!("Update Error : {e}\n"))
+                    }
+                    if self.samples.is_some() {
+                        let sample = self.telemetry();
+                        let _ = self.samples.as_ref().unwrap().send(sample);
                     }
                 }
 
                 if let Some(newcode) = Msg::try_receive_code(&code_mtx, &code_is_updated) {
                     self.new_code(newcode);
                 }
+                thread::sleep(Duration::from_millis(1));
             }
         });
 
@@ -237,130 +497,285 @@ impl Machine {
         }
     }
 
+    // runs n update cycles at once on the virtual clock, as if n periods had
+    // passed. nothing here waits, so tests can run as many cycles as they like
+    pub fn step(&mut self, n: u32) -> Result<(), RuntimeErr> {
+        for _ in 0..n {
+            self.clock_ms += self.period_ms;
+            self.exec_upd()?;
+        }
+        Ok(())
+    }
+    // virtual time in ms since the machine started
+    pub fn now_ms(&self) -> u64 {
+        self.clock_ms
+    }
+    // set by the `tick` of the program loaded last
+    pub fn period_ms(&self) -> u64 {
+        self.period_ms
+    }
     fn exec_upd(&mut self) -> Result<(), RuntimeErr> {
-        self.update.push(Insn::Halt);
-        let code = &self.update[0] as *const Insn;
-        let res = self.exec_insn(code);
-
+        // nothing to run before the first program, as on the device
+        let res = if self.update.is_empty() {
+            Ok(Value::Nil)
+        } else {
+            self.exec(self.update.clone())
+        };
+        if self.on_change {
+            for d in &mut self.node_deps {
+                d.dirty = d.always;
+            }
+        }
         res?;
-        let top = self.update.pop();
-        assert!(matches!(top, Some(Insn::Halt)));
-        write_file_append(format!("{:?}\n", self.node_v));
+        self.cycle = self.cycle.wrapping_add(1);
+        if self.trace {
+            let s = format!("{:?}\n", self.node_v);
+            self.write_trace(s);
+        }
+
+        Ok(())
+    }
 
+    fn push(&mut self, v: Value) -> Result<(), RuntimeErr> {
+        // emfrp.c gives up once rsp reaches the end of its stack
+        if self.stack.len() + 1 >= STACK_SIZE {
+            return Err(RuntimeErr::StackOverflow);
+        }
+        self.stack.push(v);
         Ok(())
     }
+    fn pop(&mut self) -> Result<Value, RuntimeErr> {
+        self.stack.pop().ok_or(RuntimeErr::StackUnderflow)
+    }
+    fn pop_int(&mut self) -> Result<i32, RuntimeErr> {
+        match self.pop()? {
+            Value::Int(i) => Ok(i),
+            v => Err(RuntimeErr::TypeMismatch("int", v)),
+        }
+    }
+    fn pop_bool(&mut self) -> Result<bool, RuntimeErr> {
+        match self.pop()? {
+            Value::Bool(b) => Ok(b),
+            v => Err(RuntimeErr::TypeMismatch("bool", v)),
+        }
+    }
+    fn node(&self, i: usize) -> Result<usize, RuntimeErr> {
+        if i < self.node_v.len() {
+            Ok(i)
+        } else {
+            Err(RuntimeErr::NoNode(i))
+        }
+    }
 
-    fn exec_insn(&mut self, insn: *const Insn) -> Result<Value, RuntimeErr> {
-        let mut rip = insn;
+    // runs encoded code until Halt or Exit, the way emfrp_exec does
+    fn exec(&mut self, code: Arc<[u8]>) -> Result<Value, RuntimeErr> {
+        let mut pc = Pc { code, at: 0 };
         let mut rbp = 0;
-        unsafe {
-            loop {
-                match rip.as_ref().unwrap() {
-                    Insn::Nil => self.stack.push(Value::Nil),
-                    Insn::Add => {
-                        let v1 = self.stack.pop().unwrap();
-                        let v2 = self.stack.pop().unwrap();
-                        match (v1, v2) {
-                            (Value::Int(i1), Value::Int(i2)) => {
-                                self.stack.push(Value::Int(i1 + i2))
-                            }
-                            _ => panic!(),
-                        }
+        self.stack.clear();
+        loop {
+            let at = pc.at;
+            match pc.byte()? {
+                op::NIL => self.push(Value::Nil)?,
+                op::INT => {
+                    let i = pc.int()?;
+                    self.push(Value::Int(i))?
+                }
+                op::BOOL => {
+                    let b = pc.byte()? != 0;
+                    self.push(Value::Bool(b))?
+                }
+                op::ADD => {
+                    let i2 = self.pop_int()?;
+                    let i1 = self.pop_int()?;
+                    self.push(Value::Int(i1.wrapping_add(i2)))?
+                }
+                op::MUL => {
+                    let i2 = self.pop_int()?;
+                    let i1 = self.pop_int()?;
+                    self.push(Value::Int(i1.wrapping_mul(i2)))?
+                }
+                op::JE8 => {
+                    let offset = pc.byte()? as i8;
+                    if self.pop_bool()? {
+                        pc.jump(offset as i32)?;
                     }
-                    Insn::Je(offset) => match self.stack.pop().unwrap() {
-                        Value::Bool(b) => {
-                            if b {
-                                rip = rip.offset(*offset as isize - 1);
-                            }
+                }
+                op::JE32 => {
+                    let offset = pc.int()?;
+                    if self.pop_bool()? {
+                        pc.jump(offset)?;
+                    }
+                }
+                op::J8 => {
+                    let offset = pc.byte()? as i8;
+                    pc.jump(offset as i32)?
+                }
+                op::J32 => {
+                    let offset = pc.int()?;
+                    pc.jump(offset)?
+                }
+                op::GET_LOCAL => {
+                    let i = pc.byte()? as usize;
+                    let v = self.stack.get(rbp + i).ok_or(RuntimeErr::NoLocal(i))?;
+                    self.push(v.clone())?
+                }
+                op::SET_LOCAL => {
+                    let i = pc.byte()? as usize;
+                    let v = self.pop()?;
+                    *self.stack.get_mut(rbp + i).ok_or(RuntimeErr::NoLocal(i))? = v;
+                }
+                op::ALLOC_NODE => {
+                    let i = self.node(pc.byte()? as usize)?;
+                    let body = pc.body()?;
+                    self.node_v[i] = self.pop()?;
+                    self.node_input_action[i] = InputAction::Insn(body);
+                    // a driver is attached again if it is still an I/O node
+                    self.node_output_action[i] = None;
+                }
+                op::ALLOC_NODE_NEW => {
+                    let i = self.node(self.nodes)?;
+                    let body = pc.body()?;
+                    self.node_v[i] = self.pop()?;
+                    self.node_input_action[i] = InputAction::Insn(body);
+                    self.nodes += 1;
+                }
+                op::UPDATE_NODE => {
+                    let i = self.node(pc.byte()? as usize)?;
+                    let action = if self.on_change && !self.node_deps[i].dirty {
+                        None
+                    } else {
+                        Some(self.node_input_action[i].clone())
+                    };
+                    match action {
+                        None => self.push(self.node_v[i].clone())?,
+                        Some(InputAction::Device(j)) => {
+                            let v = if self.node_sampling[i].due(self.clock_ms) {
+                                self.inputs[j].1.read()
+                            } else {
+                                self.node_v[i].clone()
+                            };
+                            self.push(v)?
                         }
-                        _ => panic!(),
-                    },
-                    Insn::J(offset) => rip = rip.offset(*offset as isize - 1),
-                    Insn::Mul => {
-                        let v1 = self.stack.pop().unwrap();
-                        let v2 = self.stack.pop().unwrap();
-                        match (v1, v2) {
-                            (Value::Int(i1), Value::Int(i2)) => {
-                                self.stack.push(Value::Int(i1 + i2))
-                            }
-                            _ => panic!(),
+                        Some(InputAction::Insn(body)) => {
+                            // a node body is a call without arguments
+                            let new_rbp = self.stack.len();
+                            self.push(Value::Usize(rbp))?;
+                            let ret = std::mem::replace(&mut pc, Pc { code: body, at: 0 });
+                            self.push(Value::Ret(ret))?;
+                            rbp = new_rbp;
                         }
+                        Some(InputAction::None) => self.push(Value::Nil)?,
                     }
-                    Insn::Int(i) => self.stack.push(Value::Int(*i)),
-                    Insn::Bool(b) => self.stack.push(Value::Bool(*b)),
-                    Insn::Exit => {
-                        let v = self.stack.pop().unwrap();
-                        if self.stack.is_empty() {
-                            return Ok(v);
-                        } else {
-                            panic!()
+                }
+                op::GET_NODE => {
+                    let i = self.node(pc.byte()? as usize)?;
+                    self.push(self.node_v[i].clone())?
+                }
+                op::SET_NODE => {
+                    let i = self.node(pc.byte()? as usize)?;
+                    let v = self.pop()?;
+                    if !self.on_change || self.node_deps[i].dirty {
+                        if self.on_change && self.node_v[i] != v {
+                            for j in self.node_deps[i].users.clone() {
+                                if let Some(d) = self.node_deps.get_mut(j) {
+                                    d.dirty = true;
+                                }
+                            }
                         }
-                    }
-                    Insn::GetLocal(offset) => {
-                        let v = self.stack[*offset + rbp].clone();
-                        self.stack.push(v)
-                    }
-                    Insn::SetLocal(offset) => {
-                        let v = self.stack.pop().unwrap();
-                        self.stack[*offset + rbp] = v;
-                    }
-                    Insn::AllocNode(u, insn) => {
-                        let v = self.stack.pop().unwrap();
-                        self.node_v[*u] = v;
-                        self.node_input_action[*u] = InputAction::Insn(insn.clone());
-                    }
-                    Insn::GetNode(i) => self.stack.push(self.node_v[*i].clone()),
-                    Insn::SetNode(i) => {
-                        let v = self.stack.pop().unwrap();
-                        if let Some(f) = self.node_output_action[*i] {
-                            f(&v)
+                        if let Some(j) = self.node_output_action[i] {
+                            self.outputs[j].1.write(&v)
                         }
-                        self.node_v[*i] = v;
+                        self.node_v[i] = v;
                     }
-                    Insn::Placeholder => panic!(),
-                    Insn::Halt => {
-                        assert!(self.stack.is_empty());
-                        return Ok(Value::Nil);
+                }
+                op::GET_LAST => {
+                    let i = self.node(pc.byte()? as usize)?;
+                    self.push(self.node_v_last[i].clone())?
+                }
+                op::SAVE_LAST => self.node_v_last.clone_from(&self.node_v),
+                op::ALLOC_FUNC => {
+                    let i = pc.byte()? as usize;
+                    let body = pc.body()?;
+                    *self.funcs.get_mut(i).ok_or(RuntimeErr::NoFunc(i))? = body;
+                }
+                op::ALLOC_FUNC_NEW => {
+                    let body = pc.body()?;
+                    self.funcs.push(body)
+                }
+                op::ALLOC_DATA => {
+                    let i = pc.byte()? as usize;
+                    let v = self.pop()?;
+                    *self.datas.get_mut(i).ok_or(RuntimeErr::NoData(i))? = v;
+                }
+                op::ALLOC_DATA_NEW => {
+                    let v = self.pop()?;
+                    self.datas.push(v)
+                }
+                op::RETURN => {
+                    // [args.. old_rbp ret v] -> [v]
+                    let v = self.pop()?;
+                    let (Value::Ret(ret), Value::Usize(old_rbp)) = (self.pop()?, self.pop()?)
+                    else {
+                        return Err(RuntimeErr::BadReturn);
+                    };
+                    self.stack.truncate(rbp);
+                    self.push(v)?;
+                    rbp = old_rbp;
+                    pc = ret;
+                }
+                op::CALL => {
+                    // [args.. f] -> [args.. old_rbp ret]
+                    let nargs = pc.byte()? as usize;
+                    let u = match self.pop()? {
+                        Value::Func(u) => u,
+                        v => return Err(RuntimeErr::TypeMismatch("function", v)),
+                    };
+                    let body = self.funcs.get(u).ok_or(RuntimeErr::NoFunc(u))?.clone();
+                    let new_rbp = self
+                        .stack
+                        .len()
+                        .checked_sub(nargs)
+                        .ok_or(RuntimeErr::StackUnderflow)?;
+                    self.push(Value::Usize(rbp))?;
+                    let ret = std::mem::replace(&mut pc, Pc { code: body, at: 0 });
+                    self.push(Value::Ret(ret))?;
+                    rbp = new_rbp;
+                }
+                op::EXIT => {
+                    let v = self.pop()?;
+                    if !self.stack.is_empty() {
+                        return Err(RuntimeErr::StackNotEmpty);
                     }
-                    Insn::UpdateNode(i) => match &self.node_input_action[*i] {
-                        InputAction::Device(f) => self.stack.push(f()),
-                        InputAction::Insn(insn) => {
-                            self.stack.push(Value::Usize(rbp));
-                            self.stack.push(Value::Insn(rip));
-                            rip = &insn[0] as *const Insn;
-                            rip = rip.offset(-1);
-                        }
-                        InputAction::None => {
-                            self.stack.push(Value::Nil);
-                        }
-                    },
-                    Insn::Return => {
-                        let v = self.stack.pop().unwrap();
-                        let old_rip = self.stack.pop().unwrap();
-                        let old_rbp = self.stack.pop().unwrap();
-                        self.stack.push(v);
-                        rbp = if let Value::Usize(u) = old_rbp {
-                            u
-                        } else {
-                            panic!()
-                        };
-                        rip = if let Value::Insn(u) = old_rip {
-                            u
-                        } else {
-                            panic!()
-                        };
+                    return Ok(v);
+                }
+                op::HALT => {
+                    if !self.stack.is_empty() {
+                        return Err(RuntimeErr::StackNotEmpty);
                     }
-                    Insn::Call(_) => todo!(),
-                    Insn::SaveLast => std::mem::swap(&mut self.node_v_last, &mut self.node_v),
-                    Insn::GetLast(i) => self.stack.push(self.node_v_last[*i].clone()),
-                    Insn::None => todo!(),
+                    return Ok(Value::Nil);
                 }
-                rip = rip.offset(1);
-                if DEBUG {
-                    write_file_append(format!("{:?}\n", self));
+                op::GET_FUNC => {
+                    let u = pc.byte()? as usize;
+                    self.push(Value::Func(u))?
                 }
-
-                thread::sleep(time::Duration::from_millis(100))
+                op::GET_DATA => {
+                    let i = pc.byte()? as usize;
+                    let v = self.datas.get(i).ok_or(RuntimeErr::NoData(i))?;
+                    self.push(v.clone())?
+                }
+                op::FREE_NODE => {
+                    // the slot is kept so that the indices of other nodes do not move
+                    let i = self.node(pc.byte()? as usize)?;
+                    self.node_input_action[i] = InputAction::None;
+                    self.node_output_action[i] = None;
+                    self.node_v[i] = Value::Nil;
+                    self.node_v_last[i] = Value::Nil;
+                }
+                op => return Err(RuntimeErr::InvalidOpcode(op, at)),
+            }
+            if self.trace {
+                let s = format!("{:?}\n", self);
+                self.write_trace(s);
             }
         }
     }
@@ -370,39 +785,201 @@ impl Machine {
     // new_code must return self.out something because
     // when main thread send code to machine,
     // main thread expects that machine returns msg through channel
-    fn new_code(&mut self, code: Code) {
-        match code {
-            Code::DefNode { init, upd } => {
-                let st = Instant::now();
-                let res = self.exec_insn(&init[0] as *const Insn); // codes for defining node is contained in init
-                let ed = Instant::now();
-                self.update = upd;
-                let msg = if let Ok(Value::Nil) = res {
-                    format!(
-                        "Node was defined successfully [{}us]",
-                        ed.duration_since(st).as_micros()
-                    )
-                } else {
-                    format!("Could not define node")
-                };
-                self.send_msg(msg)
-            }
-            Code::Exp(exp) => {
-                let st = Instant::now();
-                let res = self.exec_insn(&exp[0] as *const Insn);
-                let ed = Instant::now();
-                let msg = if let Ok(v) = res {
-                    format!("[OK] {:?} ({}us)", v, ed.duration_since(st).as_micros())
-                } else {
-                    format!("[ERROR]")
-                };
-                self.send_msg(msg);
-                // Exit returns value into channel, so doesn't need to send message
-            }
+    pub fn new_code(&mut self, code: Code) {
+        // every node is reported again after a load
+        self.reported.clear();
+        let (init, upd, tick_ms, drivers, deps) = match code {
+            // the image is decoded for its tables and to be checked, but the
+            // code sections are run as they came
+            Code::Image(bytes) => match Image::decode(&bytes).and_then(|image| {
+                let (init, upd) = code_sections(&bytes)?;
+                Ok((image, init.to_vec(), upd.to_vec()))
+            }) {
+                Ok((image, init, upd)) => {
+                    (init, upd, Some(image.tick_ms), image.drivers, image.deps)
+                }
+                Err(e) => return self.send_msg(format!("[ERROR] {e}")),
+            },
+            Code::DefNode { init, upd } => (
+                encode_insns(&init),
+                encode_insns(&upd),
+                None,
+                vec![],
+                vec![],
+            ),
+            Code::Exp(exp) => (encode_insns(&exp), vec![], None, vec![], vec![]),
+        };
+        let saved = self.save();
+        let st = Instant::now();
+        let res = self.exec(init.into());
+        let us = st.elapsed().as_micros();
+        let res = res.and_then(|v| self.check_deps(&deps).map(|_| v));
+        // an expression has no update section
+        if upd.is_empty() {
+            let msg = match res {
+                Ok(v) => format!("[OK] {:?} ({}us)", v, us),
+                Err(e) => {
+                    self.restore(saved);
+                    format!("[ERROR] {e}")
+                }
+            };
+            return self.send_msg(msg);
+        }
+        // codes for defining node is contained in init. the previous program
+        // keeps running if they fail
+        let err = match res {
+            Ok(Value::Nil) => None,
+            Ok(v) => Some(format!("init returned {v}")),
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(e) = err {
+            self.restore(saved);
+            return self.send_msg(format!("Could not define node: {e}"));
+        }
+        self.period_ms = match tick_ms {
+            Some(0) => UPD_FREQUENCY_MS,
+            Some(ms) => ms as u64,
+            None => self.period_ms,
+        };
+        let missing = self.attach_drivers(&drivers);
+        self.set_deps(&deps);
+        self.update = upd.into();
+        let msg = match missing.is_empty() {
+            true => format!("Node was defined successfully [{}us]", us),
+            false => format!(
+                "Node was defined successfully [{}us], no driver for {}",
+                us,
+                missing.join(", ")
+            ),
+        };
+        self.send_msg(msg)
+    }
+    fn save(&self) -> Saved {
+        Saved {
+            node_v: self.node_v.clone(),
+            node_v_last: self.node_v_last.clone(),
+            node_input_action: self.node_input_action.clone(),
+            node_output_action: self.node_output_action.clone(),
+            nodes: self.nodes,
+            funcs: self.funcs.clone(),
+            datas: self.datas.clone(),
         }
     }
+    fn restore(&mut self, saved: Saved) {
+        self.node_v = saved.node_v;
+        self.node_v_last = saved.node_v_last;
+        self.node_input_action = saved.node_input_action;
+        self.node_output_action = saved.node_output_action;
+        self.nodes = saved.nodes;
+        self.funcs = saved.funcs;
+        self.datas = saved.datas;
+    }
+}
+
+// whether an answer of new_code says that the code failed
+pub fn load_failed(msg: &str) -> bool {
+    msg.starts_with("Could not define node") || msg.starts_with("[ERROR]")
+}
+
+// a driver registered again under the same name replaces the old one
+fn register<T: ?Sized>(drivers: &mut Vec<(String, Box<T>)>, name: &str, driver: Box<T>) {
+    match drivers.iter_mut().find(|(n, _)| n == name) {
+        Some((_, d)) => *d = driver,
+        None => drivers.push((name.to_string(), driver)),
+    }
 }
 
 fn mtx_swap<T>(mtx: &Arc<Mutex<T>>, t: &mut T) {
     std::mem::swap(mtx.lock().as_deref_mut().unwrap(), t)
 }
+
+// a machine without tracing that has loaded src, after `drivers` registered
+// what it needs
+#[cfg(test)]
+pub fn test_machine(src: &str, on_change: bool, drivers: impl FnOnce(&mut Machine)) -> Machine {
+    use crate::repl::{Session, Target};
+    let mut session = Session::new(Target::None);
+    session.set_on_change(on_change);
+    let (mut machine, msgs) = Machine::new();
+    machine.set_trace(false);
+    drivers(&mut machine);
+    machine.new_code(Code::Image(session.compile("test", src).unwrap()));
+    let msg = msgs.recv().unwrap();
+    assert!(msg.starts_with("Node was defined"), "{msg}");
+    machine
+}
+
+#[test]
+fn local_machine_test() {
+    use crate::compile::*;
+    let machine = Machine::spawn();
+    let mut cmp = Compiler::new();
+    let mut load = |src: &str| {
+        let prog = crate::grammer::ProgramParser::new().parse(src).unwrap();
+        let (init, upd) = match cmp.compile(&prog).unwrap() {
+            CompiledCode::DefNode { init, upd } => (init, upd),
+            CompiledCode::Exp(e) => (e, vec![]),
+        };
+        machine.load(Code::Image(cmp.image(init, upd).encode()))
+    };
+    assert!(load("node init[0] a = a@last + 1").starts_with("Node was defined"));
+    assert!(load("1 + 2").starts_with("[OK] Int(3)"));
+}
+
+#[test]
+fn exec_bytes_test() {
+    let src = "func f(x) = if x then 1 else 2 node init[0] a = a@last + 1 node b = f(true) node c = f(false)";
+    let mut machine = test_machine(src, false, |_| ());
+    machine.step(3).unwrap();
+    assert_eq!(
+        machine.node_values(),
+        vec![(0, Value::Int(3)), (1, Value::Int(1)), (2, Value::Int(2))]
+    );
+
+    // malformed code is an error rather than a panic
+    let res = machine.exec(Arc::from([op::INT, 1, 0]));
+    assert!(matches!(res, Err(RuntimeErr::UnexpectedEnd(1))));
+    let res = machine.exec(Arc::from([op::ADD]));
+    assert!(matches!(res, Err(RuntimeErr::StackUnderflow)));
+    let res = machine.exec(Arc::from([op::J8, 0x80]));
+    assert!(matches!(res, Err(RuntimeErr::UnexpectedEnd(_))));
+}
+
+#[test]
+fn step_test() {
+    let src = "node init[0] a = a@last + 1 node init[false] b = if b@last then false else true";
+    let mut machine = test_machine(src, false, |_| ());
+    machine.step(10_000).unwrap();
+    assert_eq!(
+        machine.node_values(),
+        vec![(0, Value::Int(10_000)), (1, Value::Bool(false))]
+    );
+    assert_eq!(machine.now_ms(), 10_000 * UPD_FREQUENCY_MS);
+    let sample = machine.telemetry();
+    assert_eq!((sample.cycle, sample.time_ms), (10_000, 10_000_000));
+}
+
+#[test]
+fn on_change_test() {
+    let src = "input temp : Int output led = temp * 2 node init[0] n = n@last + 1";
+    let run = |on_change: bool| {
+        let led = Recorder::new();
+        let mut machine = test_machine(src, on_change, |machine| {
+            machine.register_input(
+                "temp",
+                Replay::new(vec![Value::Int(1), Value::Int(1), Value::Int(2)]),
+            );
+            machine.register_output("led", led.clone());
+        });
+        machine.step(4).unwrap();
+        (led.values(), machine.node_values())
+    };
+    let (full, full_nodes) = run(false);
+    let (on_change, on_change_nodes) = run(true);
+    let ints = |v: &[i32]| v.iter().map(|i| Value::Int(*i)).collect::<Vec<_>>();
+    assert_eq!(full, ints(&[2, 2, 4, 4]));
+    // led is only written when temp has changed; n, which reads @last, still counts
+    assert_eq!(on_change, ints(&[2, 4]));
+    assert_eq!(on_change_nodes, full_nodes);
+    assert_eq!(on_change_nodes[2], (2, Value::Int(4)));
+}
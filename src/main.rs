@@ -1,124 +1,218 @@
-use crate::ast::*;
-use crate::compile::*;
-use grammer::*;
+use crate::link::*;
+use crate::machine::Machine;
+use crate::repl::Target;
+use rustyline::error::ReadlineError;
+use rustyline::history::FileHistory;
+use rustyline::Editor;
 use serial2::*;
-use std::io::*;
+use std::time::Duration;
 
 use lalrpop_util::lalrpop_mod;
 
+pub mod asm;
 pub mod ast;
+pub mod batch;
 pub mod compile;
+pub mod container;
 pub mod datastructure;
 pub mod dependency;
+pub mod diagnostic;
+#[cfg(test)]
+mod diff;
+pub mod disasm;
+pub mod driver;
 pub mod emtypes;
 pub mod exec;
 pub mod insn;
+pub mod link;
+pub mod machine;
 pub mod qstr;
-lalrpop_mod!(grammer);
+pub mod repl;
+pub mod sim;
+pub mod telemetry;
+pub mod verify;
+lalrpop_mod!(
+    #[allow(clippy::all)]
+    grammer
+);
 const MACHINE_FILE: &str = "machine_state.txt";
-const UART_FILE: &str = "/dev/cu.usbserial-0001";
 const BAUD_RATE: u32 = 115200;
 const UPD_FREQUENCY_MS: u64 = 1000;
 const MAX_NUMBER_OF_NODE: usize = 100;
+const STACK_SIZE: usize = 128; // value_t stack[128] in emfrp.c
 const DEBUG: bool = true;
 const CONSOLE: &str = " > ";
-const CONSOLE2: &str = "...";
-fn main() {
-    /*let mut port = SerialPort::open(UART_FILE, BAUD_RATE).unwrap();
-    let mut settings = port.get_configuration().unwrap();
-    settings.set_stop_bits(StopBits::One);
-    settings.set_flow_control(FlowControl::None);
-    settings.set_char_size(CharSize::Bits8);
-    port.set_configuration(&settings).unwrap();
+const HISTORY_FILE: &str = ".emfrp_history"; // in the home directory
+const SOURCE_NAME: &str = "<stdin>";
+const DEFAULT_CYCLES: u32 = 10;
+const USAGE: &str = "usage: emfrp-vm-test [--port PATH] [--baud RATE] [--simulate] [--on-change]
+       emfrp-vm-test compile FILE -o OUT [--on-change]
+       emfrp-vm-test run FILE [--cycles N] [--port PATH] [--baud RATE] [--on-change]
+       emfrp-vm-test --device
 
-    port.write_all(b"hello,world").unwrap();
-
-    port.flush().unwrap();
-    println!("written");
-    let mut buf: Vec<u8> = iter::repeat(0).take(5).collect();
-    port.read(&mut buf).unwrap();
-    println!("{:?}", buf);
-    for c in port.bytes() {
-        println!("{}", c.unwrap())
-    }*/
-    let parser_prog = ProgramParser::new();
-    let parser_def = DefParser::new();
-    let mut cmp = Compiler::new();
-    for _ in 0.. {
-        stdout().flush().unwrap();
-        print!("{CONSOLE}");
-        stdout().flush().unwrap();
-        let mut input = String::new();
-        stdin().read_line(&mut input).unwrap();
-
-        let prog = if let "{" = input.trim() {
-            let mut v = vec![];
-
-            loop {
-                let mut input2 = String::new();
-                stdout().flush().unwrap();
-                print!("{CONSOLE2}");
-                stdout().flush().unwrap();
-                stdin().read_line(&mut input2).unwrap();
-                if let "}" = input2.trim() {
-                    break Program::Defs(v);
-                } else {
-                    match parser_def.parse(&input2) {
-                        Ok(res) => v.push(res),
-                        Err(msg) => {
-                            println!("parse error : expected definition or '}}'");
-                            println!("{}", msg)
-                        }
-                    }
-                }
-            }
-        } else {
-            match parser_prog.parse(&input) {
-                Ok(res) => res,
-                Err(msg) => {
-                    println!("parse error : {:?}", msg);
-                    continue;
-                }
+--on-change  update only the nodes downstream of what changed in each cycle";
+enum Command {
+    Repl { simulate: bool }, // simulate: run the code on a machine in this process
+    Device,                  // simulate a device on a pseudo terminal
+    Compile { src: String, out: String },
+    Run { src: String, cycles: u32 },
+}
+struct Args {
+    cmd: Command,
+    port: Option<String>, // without a port the code is only printed
+    baud: u32,
+    on_change: bool,
+}
+fn parse_args() -> std::result::Result<Args, String> {
+    let mut ret = Args {
+        cmd: Command::Repl { simulate: false },
+        port: None,
+        baud: BAUD_RATE,
+        on_change: false,
+    };
+    let mut args = std::env::args().skip(1).peekable();
+    let sub = args.next_if(|a| a == "compile" || a == "run");
+    let src = match &sub {
+        Some(sub) => Some(args.next().ok_or(format!("{sub} needs a file"))?),
+        None => None,
+    };
+    let mut out = None;
+    let mut cycles = None;
+    let mut device = false;
+    let mut simulate = false;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--port" => ret.port = Some(args.next().ok_or("--port needs a path")?),
+            "--baud" => {
+                let baud = args.next().ok_or("--baud needs a rate")?;
+                ret.baud = baud
+                    .parse()
+                    .map_err(|_| format!("invalid baud rate `{baud}`"))?;
             }
-        };
-        let mut ret = vec![];
-        let (init, upd) = match cmp.compile(&prog) {
-            Ok(res) => match res {
-                CompiledCode::DefNode { init, upd } => (init, upd),
-                CompiledCode::Exp(e) => (e, vec![]),
-            },
-            Err(msg) => {
-                println!("{:?}", msg);
-                continue;
+            "--on-change" => ret.on_change = true,
+            "--device" if sub.is_none() => device = true,
+            "--simulate" if sub.is_none() => simulate = true,
+            "-o" if sub.as_deref() == Some("compile") => {
+                out = Some(args.next().ok_or("-o needs a file")?)
             }
-        };
-        if DEBUG {
-            println!("init:");
-            for insn in &init {
-                println!("  {:?}", insn)
-            }
-            println!("update:");
-            for insn in &upd {
-                println!("  {:?}", insn)
+            "--cycles" if sub.as_deref() == Some("run") => {
+                let n = args.next().ok_or("--cycles needs a number")?;
+                cycles = Some(
+                    n.parse()
+                        .map_err(|_| format!("invalid number of cycles `{n}`"))?,
+                );
             }
+            _ => return Err(format!("unknown argument `{arg}`")),
+        }
+    }
+    ret.cmd = match (sub.as_deref(), src) {
+        (Some("compile"), Some(src)) => Command::Compile {
+            src,
+            out: out.ok_or("compile needs -o OUT")?,
+        },
+        (Some("run"), Some(src)) => Command::Run {
+            src,
+            cycles: cycles.unwrap_or(DEFAULT_CYCLES),
+        },
+        _ if device => Command::Device,
+        _ if simulate && ret.port.is_some() => {
+            return Err("--simulate cannot be used with --port".to_string())
         }
-        for _ in 0..8 {
-            ret.push(0);
+        _ => Command::Repl { simulate },
+    };
+    Ok(ret)
+}
+fn open_port(path: &str, baud: u32) -> std::io::Result<SerialPort> {
+    let mut port = SerialPort::open(path, baud)?;
+    let mut settings = port.get_configuration()?;
+    settings.set_stop_bits(StopBits::One);
+    settings.set_flow_control(FlowControl::None);
+    settings.set_char_size(CharSize::Bits8);
+    port.set_configuration(&settings)?;
+    port.set_read_timeout(Duration::from_millis(100))?;
+    Ok(port)
+}
+fn main() {
+    let args = parse_args().unwrap_or_else(|e| {
+        eprintln!("{e}\n{USAGE}");
+        std::process::exit(2)
+    });
+    if let Command::Device = args.cmd {
+        let res = sim::Simulator::open().and_then(|mut sim| {
+            println!("device at {}", sim.path());
+            sim.run()
+        });
+        if let Err(e) = res {
+            eprintln!("device: {e}");
+            std::process::exit(1)
         }
-        for insn in init {
-            insn.push_byte_code(&mut ret);
+        return;
+    }
+    let link = args.port.as_ref().map(|path| {
+        let port = open_port(path, args.baud).unwrap_or_else(|e| {
+            eprintln!("cannot open {path}: {e}");
+            std::process::exit(1)
+        });
+        Link::new(port)
+    });
+    let res = match args.cmd {
+        Command::Compile { src, out } => batch::compile(&src, &out, args.on_change),
+        Command::Run { src, cycles } => batch::run(&src, cycles, link, args.on_change),
+        Command::Repl { simulate: true } => {
+            return run_repl(Target::Local(Machine::spawn()), args.on_change)
         }
-        let ilen = ret.len() - 8;
-        for insn in upd {
-            insn.push_byte_code(&mut ret);
+        Command::Repl { .. } | Command::Device => {
+            return run_repl(link.map_or(Target::None, Target::Device), args.on_change)
         }
-        let ulen = ret.len() - (8 + ilen);
-        for (i, b) in (ilen as i32).to_le_bytes().iter().enumerate() {
-            ret[i] = *b;
+    };
+    if let Err(e) = res {
+        eprint!("{e}");
+        std::process::exit(1)
+    }
+}
+fn history_path() -> std::path::PathBuf {
+    let home = std::env::var_os("HOME").unwrap_or_default();
+    std::path::Path::new(&home).join(HISTORY_FILE)
+}
+fn run_repl(target: Target, on_change: bool) {
+    let mut session = repl::Session::new(target);
+    session.set_on_change(on_change);
+    let mut editor = Editor::<repl::InputHelper, FileHistory>::new().unwrap_or_else(|e| {
+        eprintln!("cannot start the line editor: {e}");
+        std::process::exit(1)
+    });
+    editor.set_helper(Some(repl::InputHelper::new()));
+    let history = history_path();
+    // there is no history on the first run
+    let _ = editor.load_history(&history);
+    loop {
+        if let Some(s) = session.telemetry() {
+            print!("{s}");
         }
-        for (i, b) in (ulen as i32).to_le_bytes().iter().enumerate() {
-            ret[4 + i] = *b;
+        let input = match editor.readline(CONSOLE) {
+            Ok(input) => input,
+            Err(ReadlineError::Interrupted) => continue,
+            Err(ReadlineError::Eof) => break,
+            Err(e) => {
+                eprintln!("{e}");
+                break;
+            }
+        };
+        if input.trim().is_empty() {
+            continue;
         }
-        println!("{:?}", ret);
+        let _ = editor.add_history_entry(input.as_str());
+        if input.trim_start().starts_with(':') {
+            match session.command(input.trim()) {
+                Some(s) => print!("{s}"),
+                None => break,
+            }
+            continue;
+        }
+        // the whole input is parsed at once so that a definition may span lines
+        print!("{}", session.eval(repl::strip_block(&input)));
+    }
+    if let Err(e) = editor.save_history(&history) {
+        eprintln!("cannot save history to {}: {e}", history.display());
     }
 }
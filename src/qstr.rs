@@ -1,7 +1,7 @@
 // this is based on micropython qstr implementation
 // https://github.com/micropython/micropython/blob/master/py/qstr.c
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct QstrIndex(usize);
+pub struct QstrIndex(pub usize);
 const POOLSIZE_MIN: usize = 1;
 
 #[derive(Debug)]
@@ -40,6 +40,15 @@ impl QstrPool {
             None
         }
     }
+    // all strings in index order
+    pub fn strs(&self) -> Vec<&str> {
+        let mut ret = match &self.parent {
+            Some(par) => par.strs(),
+            None => vec![],
+        };
+        ret.extend(self.qstrs.iter().map(|s| s.as_str()));
+        ret
+    }
     pub fn insert(mut self, s: String) -> (Self, QstrIndex) {
         match self.find(&s) {
             Some(ind) => (self, ind),
@@ -57,7 +66,7 @@ impl QstrPool {
                     (new, QstrIndex(total_prev_len))
                 } else {
                     self.qstrs.push(s);
-                    let n = self.qstrs.len();
+                    let n = self.qstrs.len() - 1 + self.total_prev_len;
                     (self, QstrIndex(n))
                 }
             }
@@ -68,9 +77,22 @@ impl QstrPool {
 #[test]
 fn qstrpool_test() {
     let mut pool = QstrPool::empty();
-    for s in ["a", "b", "c", "d", "a", "c", "e", "f", "a"] {
-        (pool, _) = pool.insert(s.to_string())
+    for (s, i) in [
+        ("a", 0),
+        ("b", 1),
+        ("c", 2),
+        ("d", 3),
+        ("a", 0),
+        ("c", 2),
+        ("e", 4),
+        ("f", 5),
+        ("a", 0),
+    ] {
+        let ind;
+        (pool, ind) = pool.insert(s.to_string());
+        assert_eq!(ind, QstrIndex(i));
     }
+    assert_eq!(pool.strs(), vec!["a", "b", "c", "d", "e", "f"]);
     for (i, s) in ["a", "b", "c", "d", "e", "f"].iter().enumerate() {
         println!("{}", s);
         assert_eq!(Some(QstrIndex(i)), pool.find(s));
@@ -0,0 +1,402 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use lalrpop_util::ParseError;
+use rustyline::completion::Completer;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::Helper;
+use serial2::SerialPort;
+
+use crate::ast::Program;
+use crate::compile::*;
+use crate::diagnostic::*;
+use crate::disasm::write_insns;
+use crate::emtypes::*;
+use crate::grammer::ProgramParser;
+use crate::link::*;
+use crate::machine::*;
+use crate::telemetry::LiveValues;
+use crate::verify::*;
+use crate::{disasm, DEBUG, SOURCE_NAME};
+
+const HELP: &str = "\
+:nodes          list nodes with their definitions and current values
+:deps x         show what node x reads and which nodes read it
+:order          show the order in which nodes are updated
+:bytecode x     disassemble the body of node x
+:load file      compile a source file and send it to the device
+:reset          forget every definition, on the device too
+:quit           leave
+";
+
+// where compiled code goes
+pub enum Target {
+    None, // the code is only printed
+    Device(Link<SerialPort>),
+    Local(LocalMachine), // --simulate
+}
+
+// a compiled program with the state of the session once it is loaded
+pub struct Build {
+    pub code: Vec<u8>,
+    pub info: String, // the type of an expression, and the disassembly if DEBUG
+    cmp: Compiler,
+    tenv: TypeEnv,
+    tables: Tables,
+}
+
+// everything the REPL knows about the program on the device
+pub struct Session {
+    parser: ProgramParser,
+    cmp: Compiler,
+    tenv: TypeEnv,
+    tables: Tables,
+    live: LiveValues,
+    target: Target,
+    on_change: bool,
+}
+
+impl Session {
+    pub fn new(target: Target) -> Self {
+        Self {
+            parser: ProgramParser::new(),
+            cmp: Compiler::new(),
+            tenv: TypeEnv::new(),
+            tables: Tables::new(),
+            live: LiveValues::new(),
+            target,
+            on_change: false,
+        }
+    }
+
+    // parses, checks and compiles a program. the session is left as it is until
+    // the build is kept, which should happen only once the code is loaded
+    pub fn build(&self, name: &str, input: &str) -> Result<Build, String> {
+        let prog = match self.parser.parse(input) {
+            Ok(res) => res,
+            Err(msg) => return Err(Diagnostic::from(&msg).render(name, input)),
+        };
+        let (tenv, ty) = match self.tenv.check(&prog) {
+            Ok(res) => res,
+            Err(msg) => return Err(Diagnostic::from(&msg).render(name, input)),
+        };
+        let mut cmp = self.cmp.clone();
+        let (init, upd) = match cmp.compile(&prog) {
+            Ok(res) => match res {
+                CompiledCode::DefNode { init, upd } => (init, upd),
+                CompiledCode::Exp(e) => (e, vec![]),
+            },
+            Err(msg) => return Err(Diagnostic::from(&msg).render(name, input)),
+        };
+        let mut info = String::new();
+        if let Some(ty) = ty {
+            writeln!(info, "type : {}", ty).unwrap();
+        }
+        let code = cmp.image(init, upd).encode();
+        if DEBUG {
+            if !matches!(prog, Program::Exp(_)) {
+                info.push_str("[dependency]");
+                for name in cmp.update_order() {
+                    write!(info, " -> {name}").unwrap();
+                }
+                info.push('\n');
+            }
+            info.push_str(&disasm::disassemble(&code).unwrap());
+        }
+        // nothing that fails here may reach the device
+        match self.tables.verify_bytes(&code) {
+            Ok(tables) => Ok(Build {
+                code,
+                info,
+                cmp,
+                tenv,
+                tables,
+            }),
+            Err(e) => Err(format!("internal error: generated code is invalid: {e}\n")),
+        }
+    }
+    // takes the definitions of a build as those on the device
+    pub fn keep(&mut self, build: Build) -> Vec<u8> {
+        self.cmp = build.cmp;
+        self.tenv = build.tenv;
+        self.tables = build.tables;
+        build.code
+    }
+
+    // for callers that load the code themselves
+    pub fn compile(&mut self, name: &str, input: &str) -> Result<Vec<u8>, String> {
+        let build = self.build(name, input)?;
+        Ok(self.keep(build))
+    }
+
+    // compiles a program and sends it to the device
+    pub fn eval(&mut self, input: &str) -> String {
+        self.eval_source(SOURCE_NAME, input)
+    }
+    fn eval_source(&mut self, name: &str, input: &str) -> String {
+        let mut build = match self.build(name, input) {
+            Ok(res) => res,
+            Err(e) => return e,
+        };
+        let info = std::mem::take(&mut build.info);
+        let (loaded, msg) = match &mut self.target {
+            Target::None => (true, format!("{:?}\n", build.code)),
+            Target::Local(machine) => {
+                let msg = machine.load(Code::Image(build.code.clone()));
+                (!load_failed(&msg), format!("machine: {msg}\n"))
+            }
+            Target::Device(link) => match link.upload(&build.code) {
+                Ok(res) => (res.code == 0, format!("device: {res}\n")),
+                Err(e) => (false, format!("upload failed: {e}\n")),
+            },
+        };
+        if loaded {
+            self.keep(build);
+        }
+        info + &msg
+    }
+
+    // see Compiler::set_on_change
+    pub fn set_on_change(&mut self, on_change: bool) {
+        self.on_change = on_change;
+        self.cmp.set_on_change(on_change);
+    }
+
+    pub fn node_names(&self) -> Vec<Option<String>> {
+        self.cmp.node_names()
+    }
+
+    // runs a `:command`; None for :quit
+    pub fn command(&mut self, line: &str) -> Option<String> {
+        let mut ret = String::new();
+        let words: Vec<&str> = line.split_whitespace().collect();
+        if words[..] == [":nodes"] {
+            // current values rather than those of the last prompt
+            self.telemetry();
+        }
+        let node = |name: &str| {
+            self.cmp
+                .node(name)
+                .ok_or_else(|| format!("no node `{name}`\n"))
+        };
+        let res = match words[..] {
+            [":quit"] | [":q"] => return None,
+            [":load"] => Err(":load needs a file\n".to_string()),
+            [":load", ..] => {
+                let path = line[":load".len()..].trim();
+                match std::fs::read_to_string(path) {
+                    Ok(src) => Ok(self.eval_source(path, &src)),
+                    Err(e) => Err(format!("cannot read {path}: {e}\n")),
+                }
+            }
+            [":help"] => Ok(HELP.to_string()),
+            [":nodes"] => {
+                for n in self.cmp.nodes() {
+                    let v = self
+                        .live
+                        .get(n.index)
+                        .map_or("-".to_string(), |v| v.to_string());
+                    writeln!(ret, "{:3}  {}  [{v}]", n.index, n.def).unwrap();
+                }
+                Ok(ret)
+            }
+            [":deps", name] => node(name).map(|n| {
+                let list = |v: &[&str]| {
+                    if v.is_empty() {
+                        "-".to_string()
+                    } else {
+                        v.join(", ")
+                    }
+                };
+                format!(
+                    "{name} reads: {}\n{name} reads @last: {}\nread by: {}\n",
+                    list(&n.deps),
+                    list(&n.last_deps),
+                    list(&n.users)
+                )
+            }),
+            [":order"] => Ok(format!("{}\n", self.cmp.update_order().join(" -> "))),
+            [":bytecode", name] => node(name).map(|n| {
+                write_insns(&mut ret, n.body, 2);
+                ret
+            }),
+            [":reset"] => {
+                // the definitions are kept until the device has dropped them too
+                let msg = match &mut self.target {
+                    Target::None | Target::Local(_) => "reset\n".to_string(),
+                    Target::Device(link) => match link.reset() {
+                        Ok(res) if res.code == 0 => format!("device: {res}\n"),
+                        Ok(res) => return Some(format!("device: {res}\n")),
+                        Err(e) => return Some(format!("reset failed: {e}\n")),
+                    },
+                };
+                let target = match std::mem::replace(&mut self.target, Target::None) {
+                    // the old machine stops when it is dropped
+                    Target::Local(_) => Target::Local(Machine::spawn()),
+                    target => target,
+                };
+                let on_change = self.on_change;
+                *self = Session::new(target);
+                self.set_on_change(on_change);
+                Ok(msg)
+            }
+            [":deps" | ":bytecode"] => Err(format!("usage: {} NODE\n", words[0])),
+            _ => Err(format!("unknown command `{}` (try :help)\n", words[0])),
+        };
+        Some(res.unwrap_or_else(|e| e))
+    }
+
+    // what the device has sent since the last call, or None if nothing arrived
+    pub fn telemetry(&mut self) -> Option<String> {
+        let mut updated = false;
+        match &mut self.target {
+            Target::None => return None,
+            Target::Local(machine) => {
+                for sample in machine.samples() {
+                    self.live.apply(sample);
+                    updated = true;
+                }
+            }
+            Target::Device(link) => loop {
+                match link.telemetry(Duration::ZERO) {
+                    Ok(Some(sample)) => {
+                        self.live.apply(sample);
+                        updated = true;
+                    }
+                    Ok(None) => break,
+                    Err(e) => return Some(format!("telemetry: {e}\n")),
+                }
+            },
+        }
+        let s = self
+            .live
+            .render(&self.cmp.node_names())
+            .filter(|_| updated)?;
+        Some(format!("{s}\n"))
+    }
+}
+
+// whether the input so far needs more lines: an open `{` block, or a program
+// that ends too early such as `node a = if b then` or `f(1,`
+pub fn needs_more(parser: &ProgramParser, input: &str) -> bool {
+    let input = input.trim();
+    if input.starts_with('{') {
+        return !input.ends_with('}');
+    }
+    !input.is_empty()
+        && !input.starts_with(':')
+        && matches!(parser.parse(input), Err(ParseError::UnrecognizedEof { .. }))
+}
+// the program inside a `{ ... }` block, which lets several definitions be sent at once
+pub fn strip_block(input: &str) -> &str {
+    let t = input.trim();
+    match t.strip_prefix('{').and_then(|t| t.strip_suffix('}')) {
+        Some(body) => body,
+        None => input,
+    }
+}
+
+// lets the line editor continue incomplete input on the next line
+pub struct InputHelper {
+    parser: ProgramParser,
+}
+impl InputHelper {
+    pub fn new() -> Self {
+        Self {
+            parser: ProgramParser::new(),
+        }
+    }
+}
+impl Default for InputHelper {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl Validator for InputHelper {
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if needs_more(&self.parser, ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+impl Completer for InputHelper {
+    type Candidate = String;
+}
+impl Hinter for InputHelper {
+    type Hint = String;
+}
+impl Highlighter for InputHelper {}
+impl Helper for InputHelper {}
+
+#[test]
+fn needs_more_test() {
+    let p = ProgramParser::new();
+    assert!(needs_more(&p, "node a ="));
+    assert!(needs_more(&p, "node a = if b then 1"));
+    assert!(needs_more(&p, "node a = f(1,\n"));
+    assert!(needs_more(&p, "{\nnode a = 1\n"));
+    assert!(!needs_more(&p, "node a = if b then 1 else 2"));
+    assert!(!needs_more(&p, "node a = 1 +* 2")); // an error, not a continuation
+    assert!(!needs_more(&p, ""));
+    assert!(!needs_more(&p, ":deps a"));
+    assert_eq!(
+        strip_block("{\nnode a = 1\nnode b = a\n}"),
+        "\nnode a = 1\nnode b = a\n"
+    );
+}
+
+#[test]
+fn repl_commands_test() {
+    let mut s = Session::new(Target::None);
+    s.eval("node init[0] a = a@last + 1 node b = a * 2 node c = b + a");
+    assert_eq!(s.command(":order").unwrap(), "a -> b -> c\n");
+    assert_eq!(
+        s.command(":deps a").unwrap(),
+        "a reads: -\na reads @last: a\nread by: b, c\n"
+    );
+    assert_eq!(
+        s.command(":deps c").unwrap(),
+        "c reads: b, a\nc reads @last: -\nread by: -\n"
+    );
+    assert_eq!(
+        s.command(":nodes").unwrap(),
+        "  0  node init[0] a = a@last + 1  [-]\n  1  node b = a * 2  [-]\n  2  node c = b + a  [-]\n"
+    );
+    assert!(s.command(":bytecode b").unwrap().contains("getnode 0"));
+    assert_eq!(s.command(":deps x").unwrap(), "no node `x`\n");
+    assert_eq!(s.command(":reset").unwrap(), "reset\n");
+    assert_eq!(s.command(":nodes").unwrap(), "");
+    assert!(s.command(":quit").is_none());
+}
+
+#[test]
+fn build_test() {
+    let mut s = Session::new(Target::None);
+    // a build that is not kept, as after a failed upload, leaves no trace
+    s.build("test", "node a = 1").unwrap();
+    assert!(s.build("test", "node b = a").is_err());
+    assert!(s
+        .build("test", "1 + 2")
+        .unwrap()
+        .info
+        .starts_with("type : Int\n"));
+    assert_eq!(s.command(":nodes").unwrap(), "");
+    s.compile("test", "node a = 1").unwrap();
+    s.compile("test", "node b = a").unwrap();
+    // reported by the compiler rather than found invalid afterwards
+    let err = s
+        .build("test", "func f(b, n) = if b then n else f(true, n + 1)")
+        .err()
+        .unwrap();
+    assert!(err.contains("recursive function: f -> f"), "{err}");
+
+    // the machine has the node once it has run init
+    let mut s = Session::new(Target::Local(Machine::spawn()));
+    let msg = s.eval("node init[1] a = a@last");
+    assert!(msg.contains("machine: Node was defined"), "{msg}");
+    assert!(s.eval("a + 1").contains("machine: [OK] Int(2)"));
+}
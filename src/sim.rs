@@ -0,0 +1,217 @@
+use std::fs::File;
+use std::io::{ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
+
+use crate::container::*;
+use crate::link::*;
+use crate::machine::*;
+
+// a device on the far side of a pseudo terminal, for testing the serial path
+// without a board. the host opens path() like /dev/ttyUSB0
+pub struct Simulator {
+    master: File,
+    _slave: File, // kept open so that the master does not see EIO while no host is attached
+    path: String,
+    link: DeviceLink,
+    machine: Machine,
+    msgs: Receiver<String>,
+}
+
+impl Simulator {
+    pub fn open() -> std::io::Result<Self> {
+        let (master, path) = unsafe {
+            let fd = check(libc::posix_openpt(
+                libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+            ))?;
+            let master = File::from_raw_fd(fd);
+            check(libc::grantpt(fd))?;
+            check(libc::unlockpt(fd))?;
+            let mut name = [0 as libc::c_char; 128];
+            let e = libc::ptsname_r(fd, name.as_mut_ptr(), name.len());
+            if e != 0 {
+                return Err(std::io::Error::from_raw_os_error(e));
+            }
+            let path = std::ffi::CStr::from_ptr(name.as_ptr())
+                .to_string_lossy()
+                .into_owned();
+            // values are dropped rather than blocking while nobody reads them
+            let flags = check(libc::fcntl(fd, libc::F_GETFL))?;
+            check(libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK))?;
+            (master, path)
+        };
+        let slave = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(&path)?;
+        // no echo or line editing until the host configures the port
+        unsafe {
+            let mut t = std::mem::zeroed::<libc::termios>();
+            check(libc::tcgetattr(slave.as_raw_fd(), &mut t))?;
+            libc::cfmakeraw(&mut t);
+            check(libc::tcsetattr(slave.as_raw_fd(), libc::TCSANOW, &t))?;
+        }
+        let (machine, msgs) = Machine::new();
+        Ok(Self {
+            master,
+            _slave: slave,
+            path,
+            link: DeviceLink::new(),
+            machine,
+            msgs,
+        })
+    }
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+    // runs an update cycle every tick of the program loaded last (by default
+    // UPD_FREQUENCY_MS) and answers the host in between
+    pub fn run(&mut self) -> std::io::Result<()> {
+        let mut next = Instant::now() + Duration::from_millis(self.machine.period_ms());
+        loop {
+            self.poll(next.saturating_duration_since(Instant::now()))?;
+            if Instant::now() >= next {
+                self.tick()?;
+                next += Duration::from_millis(self.machine.period_ms());
+            }
+        }
+    }
+    // handles what the host has sent, waiting at most timeout for it
+    pub fn poll(&mut self, timeout: Duration) -> std::io::Result<()> {
+        let mut fds = libc::pollfd {
+            fd: self.master.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let ms = timeout.as_millis().min(i32::MAX as u128) as i32;
+        if unsafe { libc::poll(&mut fds, 1, ms) } <= 0 {
+            return Ok(());
+        }
+        let mut buf = [0; 256];
+        let n = match self.master.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let (mut out, req) = self.link.input(&buf[..n]);
+        match req {
+            Some(Request::Load(program)) => {
+                let (code, msg) = self.load(program);
+                out.extend(self.link.result(code, &msg));
+            }
+            Some(Request::Reset) => {
+                (self.machine, self.msgs) = Machine::new();
+                out.extend(self.link.result(0, ""));
+            }
+            None => (),
+        }
+        self.send(&out)
+    }
+    // one update cycle, followed by its telemetry
+    pub fn tick(&mut self) -> std::io::Result<()> {
+        if let Err(e) = self.machine.step(1) {
+            eprintln!("update error: {e}");
+        }
+        let sample = self.machine.telemetry().encode();
+        self.send(&sample)
+    }
+    // codes follow load_result_t in emfrp.c
+    fn load(&mut self, program: Vec<u8>) -> (u8, String) {
+        if let Err(e) = Image::decode(&program) {
+            let code = match e {
+                ContainerErr::BadMagic => 1,
+                ContainerErr::UnsupportedFormat(_) | ContainerErr::UnsupportedOpcodeSet(_) => 2,
+                ContainerErr::BadLength(_) => 3,
+                ContainerErr::BadChecksum => 4,
+                _ => 5,
+            };
+            return (code, e.to_string());
+        }
+        self.machine.new_code(Code::Image(program));
+        let msg = self.msgs.recv().unwrap_or_default();
+        (if load_failed(&msg) { 5 } else { 0 }, msg)
+    }
+    fn send(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        match self.master.write_all(bytes) {
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+            res => res,
+        }
+    }
+}
+
+fn check(ret: libc::c_int) -> std::io::Result<libc::c_int> {
+    if ret < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(ret)
+    }
+}
+
+#[test]
+fn simulator_test() {
+    use crate::compile::*;
+    use crate::grammer::ProgramParser;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let mut sim = Simulator::open().unwrap();
+    let path = sim.path().to_string();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop2 = stop.clone();
+    let device = std::thread::spawn(move || {
+        while !stop2.load(Ordering::Relaxed) {
+            sim.poll(Duration::from_millis(10)).unwrap();
+            sim.tick().unwrap();
+        }
+    });
+
+    let mut cmp = Compiler::new();
+    let prog = ProgramParser::new()
+        .parse("node init[0] a = a@last + 1")
+        .unwrap();
+    let CompiledCode::DefNode { init, upd } = cmp.compile(&prog).unwrap() else {
+        panic!()
+    };
+    let mut port = serial2::SerialPort::open(&path, 115200).unwrap();
+    port.set_read_timeout(Duration::from_millis(100)).unwrap();
+    let mut link = Link::new(port);
+    let res = link.upload(&cmp.image(init, upd).encode()).unwrap();
+    assert_eq!(res.code, 0, "{res}");
+    // a counts up once per cycle
+    let mut seen = vec![];
+    for _ in 0..20 {
+        if seen.len() == 2 {
+            break;
+        }
+        if let Some(sample) = link.telemetry(Duration::from_secs(1)).unwrap() {
+            if let [(0, Value::Int(n))] = sample.changed[..] {
+                seen.push((sample.cycle, n));
+            }
+        }
+    }
+    assert!(seen.len() == 2 && seen[1] > seen[0], "{seen:?}");
+    stop.store(true, Ordering::Relaxed);
+    device.join().unwrap();
+}
+
+#[test]
+fn simulator_load_test() {
+    use crate::container::Image;
+    use crate::insn::Insn;
+
+    let mut sim = Simulator::open().unwrap();
+    sim.machine.set_trace(false);
+    // init pops from an empty stack
+    let image = Image {
+        tick_ms: 0,
+        init: vec![Insn::Add, Insn::Halt],
+        update: vec![Insn::Halt],
+        funcs: vec![],
+        datas: vec![],
+        drivers: vec![],
+        deps: vec![],
+    };
+    let (code, msg) = sim.load(image.encode());
+    assert_eq!(code, 5, "{msg}");
+}
@@ -0,0 +1,149 @@
+use crate::link::*;
+use crate::machine::Value;
+
+// what the device sends after every update cycle, as the payload of Telemetry
+// frames (integers are little endian)
+//
+//   cycle: u32, time in ms since the device started: u32,
+//   per node whose value changed: index: u8, tag: u8, value: i32
+//
+// a cycle with many changes is split over frames with the same header. after a
+// program is loaded every live node counts as changed
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 6;
+const VALUE_NIL: u8 = 0;
+const VALUE_INT: u8 = 1;
+const VALUE_BOOL: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub cycle: u32,
+    pub time_ms: u32,
+    pub changed: Vec<(usize, Value)>, // by node index
+}
+
+impl Sample {
+    // values other than Int and Bool are sent as nil
+    pub fn encode(&self) -> Vec<u8> {
+        let per_frame = (MAX_PAYLOAD - HEADER_SIZE) / ENTRY_SIZE;
+        let nframes = self.changed.len().div_ceil(per_frame).max(1);
+        let mut ret = vec![];
+        for k in 0..nframes {
+            let chunk = &self.changed[k * per_frame..self.changed.len().min((k + 1) * per_frame)];
+            let mut payload = self.cycle.to_le_bytes().to_vec();
+            payload.extend(self.time_ms.to_le_bytes());
+            for (i, v) in chunk {
+                let (tag, n) = match v {
+                    Value::Int(n) => (VALUE_INT, *n),
+                    Value::Bool(b) => (VALUE_BOOL, *b as i32),
+                    _ => (VALUE_NIL, 0),
+                };
+                payload.extend([*i as u8, tag]);
+                payload.extend(n.to_le_bytes());
+            }
+            ret.extend(Frame::new(FrameKind::Telemetry, 0, payload).encode());
+        }
+        ret
+    }
+    pub fn decode(payload: &[u8]) -> Option<Sample> {
+        if payload.len() < HEADER_SIZE || !(payload.len() - HEADER_SIZE).is_multiple_of(ENTRY_SIZE)
+        {
+            return None;
+        }
+        let u32_at = |i: usize| {
+            u32::from_le_bytes([payload[i], payload[i + 1], payload[i + 2], payload[i + 3]])
+        };
+        let changed = payload[HEADER_SIZE..]
+            .chunks(ENTRY_SIZE)
+            .map(|e| {
+                let n = i32::from_le_bytes([e[2], e[3], e[4], e[5]]);
+                let v = match e[1] {
+                    VALUE_INT => Value::Int(n),
+                    VALUE_BOOL => Value::Bool(n != 0),
+                    _ => Value::Nil,
+                };
+                (e[0] as usize, v)
+            })
+            .collect();
+        Some(Sample {
+            cycle: u32_at(0),
+            time_ms: u32_at(4),
+            changed,
+        })
+    }
+}
+
+// the host's picture of the device, built from the samples received so far
+#[derive(Debug, Default)]
+pub struct LiveValues {
+    cycle: Option<(u32, u32)>,
+    values: Vec<Option<Value>>,
+}
+impl LiveValues {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn apply(&mut self, sample: Sample) {
+        self.cycle = Some((sample.cycle, sample.time_ms));
+        for (i, v) in sample.changed {
+            if self.values.len() <= i {
+                self.values.resize(i + 1, None);
+            }
+            self.values[i] = Some(v);
+        }
+    }
+    pub fn get(&self, i: usize) -> Option<&Value> {
+        self.values.get(i)?.as_ref()
+    }
+    // names are by node index as given by Compiler::node_names; deleted nodes are None
+    pub fn render(&self, names: &[Option<String>]) -> Option<String> {
+        let (cycle, time_ms) = self.cycle?;
+        let values: Vec<String> = names
+            .iter()
+            .enumerate()
+            .filter_map(|(i, name)| {
+                let v = self.values.get(i)?.as_ref()?;
+                Some(format!("{} = {}", name.as_ref()?, v))
+            })
+            .collect();
+        if values.is_empty() {
+            return None;
+        }
+        Some(format!(
+            "[cycle {cycle} at {time_ms} ms] {}",
+            values.join(", ")
+        ))
+    }
+}
+
+#[test]
+fn telemetry_test() {
+    let sample = Sample {
+        cycle: 7,
+        time_ms: 7000,
+        changed: (0..50).map(|i| (i, Value::Int(i as i32 * 3))).collect(),
+    };
+    // 50 entries do not fit in one frame
+    let bytes = sample.encode();
+    let mut decoder = FrameDecoder::new();
+    let mut live = LiveValues::new();
+    let mut frames = 0;
+    for b in bytes {
+        if let Some(Ok(frame)) = decoder.push(b) {
+            assert_eq!(frame.kind, FrameKind::Telemetry);
+            live.apply(Sample::decode(&frame.payload).unwrap());
+            frames += 1;
+        }
+    }
+    assert_eq!(frames, 2);
+    live.apply(Sample {
+        cycle: 8,
+        time_ms: 8000,
+        changed: vec![(1, Value::Bool(true))],
+    });
+    let names = vec![Some("a".to_string()), Some("b".to_string()), None];
+    assert_eq!(
+        live.render(&names).unwrap(),
+        "[cycle 8 at 8000 ms] a = 0, b = true"
+    );
+}
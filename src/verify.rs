@@ -0,0 +1,468 @@
+use std::collections::HashMap;
+
+use crate::container::*;
+use crate::insn::*;
+use crate::STACK_SIZE;
+
+// what is allocated on the device, so that indices and stack usage of code
+// referring to earlier uploads can be checked
+#[derive(Debug, Clone, Default)]
+pub struct Tables {
+    pub nodes: Vec<Option<usize>>, // stack slots used by the update of each node, None if freed
+    pub funcs: Vec<FuncSig>,
+    pub datas: usize,
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FuncSig {
+    pub min_args: usize, // 1 + the largest GetLocal/SetLocal index in the body
+    pub need: usize,     // stack slots used above the arguments, including the saved rbp and rip
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    Init(usize), // byte offset in the section or body
+    Update(usize),
+    Node(NodeOffset, usize),
+    Func(FuncOffset, usize),
+}
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerifyErr {
+    Load(ContainerErr),
+    NotAllowed(Location, &'static str), // instruction, where it cannot be used
+    BadJumpTarget(Location, i64),
+    StackUnderflow(Location),
+    DepthMismatch(Location, usize, usize),
+    BadDepthAtEnd(Location, &'static str, usize), // Halt/Exit/Return, depth found
+    FallsOffEnd(Location),
+    IndexOutOfRange(Location, &'static str, usize),
+    FreedNode(Location, NodeOffset),
+    UnknownCallee(Location),
+    TooFewArgs(Location, FuncOffset, usize, usize),
+    RecursiveCall(Location, FuncOffset),
+    StackOverflow(usize),
+    BadDriver(String), // names a node that does not exist
+    BadDeps(usize),    // a node in the dependencies does not exist
+}
+type VResult<T> = Result<T, VerifyErr>;
+
+impl Tables {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    // returns the tables after the code has been run (self is left untouched, as
+    // in TypeEnv::check). indices are checked against the tables after init, since
+    // node bodies may refer to nodes that are allocated after them
+    pub fn verify(&self, init: &[Insn], upd: &[Insn]) -> VResult<Tables> {
+        let mut v = Verifier {
+            tables: self.clone(),
+            funcs: HashMap::new(),
+            visiting: vec![],
+        };
+        let mut nodes = vec![];
+        for (code, loc) in [
+            (init, Location::Init as fn(usize) -> Location),
+            (upd, Location::Update),
+        ] {
+            let mut offset = 0;
+            for insn in code {
+                v.collect(insn, loc(offset), &mut nodes)?;
+                offset += bytecode_len(std::slice::from_ref(insn));
+            }
+        }
+        let pending: Vec<FuncOffset> = v.funcs.keys().copied().collect();
+        for i in pending {
+            v.func(i, Location::Func(i, 0))?;
+        }
+        for (i, body) in nodes {
+            let (need, _) = v.analyze(body, Kind::Node, &|o| Location::Node(i, o))?;
+            v.tables.nodes[i] = Some(need);
+        }
+        for (code, loc) in [
+            (init, Location::Init as fn(usize) -> Location),
+            (upd, Location::Update),
+        ] {
+            let (peak, _) = v.analyze(code, Kind::TopLevel, &loc)?;
+            if peak > STACK_SIZE {
+                return Err(VerifyErr::StackOverflow(peak));
+            }
+        }
+        Ok(v.tables)
+    }
+    pub fn verify_bytes(&self, bytes: &[u8]) -> VResult<Tables> {
+        let image = Image::decode(bytes).map_err(VerifyErr::Load)?;
+        let tables = self.verify(&image.init, &image.update)?;
+        for d in image.drivers {
+            if !matches!(tables.nodes.get(d.node), Some(Some(_))) {
+                return Err(VerifyErr::BadDriver(d.name));
+            }
+        }
+        for d in image.deps {
+            for i in std::iter::once(d.node).chain(d.users) {
+                if !matches!(tables.nodes.get(i), Some(Some(_))) {
+                    return Err(VerifyErr::BadDeps(i));
+                }
+            }
+        }
+        Ok(tables)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    TopLevel,
+    Node,
+    Func,
+}
+struct Verifier<'a> {
+    tables: Tables,
+    funcs: HashMap<FuncOffset, &'a [Insn]>, // bodies that have not been analyzed yet
+    visiting: Vec<FuncOffset>,
+}
+impl<'a> Verifier<'a> {
+    // registers what a top level instruction allocates
+    fn collect(
+        &mut self,
+        insn: &'a Insn,
+        at: Location,
+        nodes: &mut Vec<(NodeOffset, &'a [Insn])>,
+    ) -> VResult<()> {
+        match insn {
+            Insn::AllocNode(i, body) => {
+                self.index(at, "node", *i, self.tables.nodes.len())?;
+                self.tables.nodes[*i] = Some(0);
+                nodes.retain(|(j, _)| j != i);
+                nodes.push((*i, body));
+            }
+            Insn::AllocNodeNew(body) => {
+                nodes.push((self.tables.nodes.len(), body));
+                self.tables.nodes.push(Some(0));
+            }
+            Insn::AllocFunc(i, body) => {
+                self.index(at, "function", *i, self.tables.funcs.len())?;
+                self.funcs.insert(*i, body);
+            }
+            Insn::AllocFuncNew(body) => {
+                self.funcs.insert(self.tables.funcs.len(), body);
+                self.tables.funcs.push(FuncSig {
+                    min_args: 0,
+                    need: 0,
+                });
+            }
+            Insn::AllocData(i) => self.index(at, "data", *i, self.tables.datas)?,
+            Insn::AllocDataNew => self.tables.datas += 1,
+            Insn::FreeNode(i) => {
+                self.index(at, "node", *i, self.tables.nodes.len())?;
+                self.tables.nodes[*i] = None;
+                nodes.retain(|(j, _)| j != i);
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+    fn index(&self, at: Location, what: &'static str, i: usize, len: usize) -> VResult<()> {
+        if i < len {
+            Ok(())
+        } else {
+            Err(VerifyErr::IndexOutOfRange(at, what, i))
+        }
+    }
+    fn func(&mut self, i: FuncOffset, at: Location) -> VResult<FuncSig> {
+        if self.visiting.contains(&i) {
+            return Err(VerifyErr::RecursiveCall(at, i));
+        }
+        if let Some(body) = self.funcs.remove(&i) {
+            self.visiting.push(i);
+            let (need, min_args) = self.analyze(body, Kind::Func, &|o| Location::Func(i, o))?;
+            self.visiting.pop();
+            self.tables.funcs[i] = FuncSig { min_args, need };
+        }
+        Ok(self.tables.funcs[i])
+    }
+    // follows every path through the code and returns the largest stack depth
+    // (including callees) and the number of arguments the code reads
+    fn analyze(
+        &mut self,
+        code: &[Insn],
+        kind: Kind,
+        loc: &dyn Fn(usize) -> Location,
+    ) -> VResult<(usize, usize)> {
+        let mut offsets = Vec::with_capacity(code.len());
+        let mut offset = 0;
+        for insn in code {
+            offsets.push(offset);
+            offset += bytecode_len(std::slice::from_ref(insn));
+        }
+        if code.is_empty() {
+            return match kind {
+                Kind::TopLevel => Ok((0, 0)),
+                _ => Err(VerifyErr::FallsOffEnd(loc(0))),
+            };
+        }
+        // a node or function body starts after the saved rbp and return address
+        let base = if kind == Kind::TopLevel { 0 } else { 2 };
+        let mut depth: Vec<Option<usize>> = vec![None; code.len()];
+        let mut work = vec![(0, base)];
+        let mut peak = base;
+        let mut nargs = 0;
+        while let Some((i, d)) = work.pop() {
+            let at = loc(offsets[i]);
+            match depth[i] {
+                Some(x) if x == d => continue,
+                Some(x) => return Err(VerifyErr::DepthMismatch(at, x, d)),
+                None => depth[i] = Some(d),
+            }
+            let not_allowed = |s| Err(VerifyErr::NotAllowed(at, s));
+            let top_level = kind == Kind::TopLevel;
+            let (pop, push, extra) = match &code[i] {
+                Insn::Nil | Insn::Int(_) | Insn::Bool(_) => (0, 1, 0),
+                Insn::Add | Insn::Mul => (2, 1, 0),
+                Insn::Je8(_) | Insn::Je32(_) => (1, 0, 0),
+                Insn::J8(_) | Insn::J32(_) | Insn::SaveLast => (0, 0, 0),
+                Insn::GetLocal(u) | Insn::SetLocal(u) => {
+                    if kind != Kind::Func {
+                        return not_allowed("outside of a function");
+                    }
+                    nargs = nargs.max(u + 1);
+                    if let Insn::GetLocal(_) = code[i] {
+                        (0, 1, 0)
+                    } else {
+                        (1, 0, 0)
+                    }
+                }
+                Insn::AllocNode(..) | Insn::AllocNodeNew(_) | Insn::AllocDataNew if top_level => {
+                    (1, 0, 0)
+                }
+                Insn::AllocData(u) if top_level => {
+                    self.index(at, "data", *u, self.tables.datas)?;
+                    (1, 0, 0)
+                }
+                Insn::AllocFunc(..) | Insn::AllocFuncNew(_) | Insn::FreeNode(_) if top_level => {
+                    (0, 0, 0)
+                }
+                Insn::AllocNode(..)
+                | Insn::AllocNodeNew(_)
+                | Insn::AllocData(_)
+                | Insn::AllocDataNew
+                | Insn::AllocFunc(..)
+                | Insn::AllocFuncNew(_)
+                | Insn::FreeNode(_) => return not_allowed("inside a node or function body"),
+                Insn::GetFunc(f) => {
+                    self.index(at, "function", *f, self.tables.funcs.len())?;
+                    // the callee has to be known to bound the stack, so GetFunc and Call
+                    // are checked as a pair
+                    let Some(Insn::Call(n)) = code.get(i + 1) else {
+                        return Err(VerifyErr::UnknownCallee(at));
+                    };
+                    let sig = self.func(*f, at)?;
+                    if *n < sig.min_args {
+                        return Err(VerifyErr::TooFewArgs(at, *f, sig.min_args, *n));
+                    }
+                    if d < base + n {
+                        return Err(VerifyErr::StackUnderflow(at));
+                    }
+                    peak = peak.max(d + sig.need);
+                    let nd = d - n + 1;
+                    self.next(code, i + 1, nd, loc, &offsets, &mut work)?;
+                    continue;
+                }
+                Insn::Call(_) => return Err(VerifyErr::UnknownCallee(at)),
+                Insn::GetData(u) => {
+                    self.index(at, "data", *u, self.tables.datas)?;
+                    (0, 1, 0)
+                }
+                Insn::UpdateNode(u) if top_level => {
+                    self.index(at, "node", *u, self.tables.nodes.len())?;
+                    match self.tables.nodes[*u] {
+                        Some(need) => (0, 1, need),
+                        None => return Err(VerifyErr::FreedNode(at, *u)),
+                    }
+                }
+                Insn::UpdateNode(_) => return not_allowed("inside a node or function body"),
+                Insn::GetNode(u) | Insn::GetLast(u) | Insn::SetNode(u) => {
+                    self.index(at, "node", *u, self.tables.nodes.len())?;
+                    if self.tables.nodes[*u].is_none() {
+                        return Err(VerifyErr::FreedNode(at, *u));
+                    }
+                    if let Insn::SetNode(_) = code[i] {
+                        (1, 0, 0)
+                    } else {
+                        (0, 1, 0)
+                    }
+                }
+                Insn::Halt | Insn::Exit if !top_level => {
+                    return not_allowed("inside a node or function body")
+                }
+                Insn::Return if top_level => return not_allowed("at the top level"),
+                Insn::Halt | Insn::Exit | Insn::Return => {
+                    let (name, expected) = match code[i] {
+                        Insn::Halt => ("halt", 0),
+                        Insn::Exit => ("exit", 1),
+                        _ => ("return", base + 1),
+                    };
+                    if d != expected {
+                        return Err(VerifyErr::BadDepthAtEnd(at, name, d - base));
+                    }
+                    continue;
+                }
+                Insn::None | Insn::Placeholder => return not_allowed("anywhere"),
+            };
+            if d < base + pop {
+                return Err(VerifyErr::StackUnderflow(at));
+            }
+            let nd = d - pop + push;
+            peak = peak.max(nd).max(d + extra);
+            match &code[i] {
+                Insn::Je8(_) | Insn::Je32(_) | Insn::J8(_) | Insn::J32(_) => {
+                    let rel = match code[i] {
+                        Insn::Je8(r) | Insn::J8(r) => r as i64,
+                        Insn::Je32(r) | Insn::J32(r) => r as i64,
+                        _ => unreachable!(),
+                    };
+                    let target = offsets[i] as i64 + bytecode_len(&code[i..i + 1]) as i64 + rel;
+                    match offsets.iter().position(|o| *o as i64 == target) {
+                        Some(t) => work.push((t, nd)),
+                        None => return Err(VerifyErr::BadJumpTarget(at, target)),
+                    }
+                    if let Insn::Je8(_) | Insn::Je32(_) = code[i] {
+                        self.next(code, i, nd, loc, &offsets, &mut work)?;
+                    }
+                }
+                _ => self.next(code, i, nd, loc, &offsets, &mut work)?,
+            }
+        }
+        Ok((peak, nargs))
+    }
+    fn next(
+        &self,
+        code: &[Insn],
+        i: usize,
+        d: usize,
+        loc: &dyn Fn(usize) -> Location,
+        offsets: &[usize],
+        work: &mut Vec<(usize, usize)>,
+    ) -> VResult<()> {
+        if i + 1 == code.len() {
+            return Err(VerifyErr::FallsOffEnd(loc(offsets[i])));
+        }
+        work.push((i + 1, d));
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Location::Init(o) => write!(f, "init+{o:04x}"),
+            Location::Update(o) => write!(f, "update+{o:04x}"),
+            Location::Node(i, o) => write!(f, "node {i}+{o:04x}"),
+            Location::Func(i, o) => write!(f, "func {i}+{o:04x}"),
+        }
+    }
+}
+impl std::fmt::Display for VerifyErr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyErr::Load(e) => write!(f, "{e}"),
+            VerifyErr::NotAllowed(at, s) => write!(f, "{at}: instruction not allowed {s}"),
+            VerifyErr::BadJumpTarget(at, t) => {
+                write!(f, "{at}: jump target {t:04x} is not an instruction")
+            }
+            VerifyErr::StackUnderflow(at) => write!(f, "{at}: stack underflow"),
+            VerifyErr::DepthMismatch(at, x, y) => {
+                write!(f, "{at}: stack depth is {x} on one path and {y} on another")
+            }
+            VerifyErr::BadDepthAtEnd(at, name, d) => {
+                write!(f, "{at}: `{name}` with {d} value(s) on the stack")
+            }
+            VerifyErr::FallsOffEnd(at) => write!(f, "{at}: execution falls off the end"),
+            VerifyErr::IndexOutOfRange(at, what, i) => write!(f, "{at}: no {what} {i}"),
+            VerifyErr::FreedNode(at, i) => write!(f, "{at}: node {i} has been deleted"),
+            VerifyErr::UnknownCallee(at) => {
+                write!(f, "{at}: `call` must directly follow `getfunc`")
+            }
+            VerifyErr::TooFewArgs(at, i, expected, found) => write!(
+                f,
+                "{at}: func {i} reads {expected} argument(s) but {found} were supplied"
+            ),
+            VerifyErr::RecursiveCall(at, i) => write!(f, "{at}: func {i} calls itself"),
+            VerifyErr::StackOverflow(d) => write!(
+                f,
+                "code needs {d} stack slots but the device has {STACK_SIZE}"
+            ),
+            VerifyErr::BadDriver(name) => write!(f, "driver `{name}` is bound to no node"),
+            VerifyErr::BadDeps(i) => write!(f, "dependencies refer to missing node {i}"),
+        }
+    }
+}
+
+#[test]
+fn verify_test() {
+    use crate::asm::assemble_insns;
+    let t = Tables::new();
+    let ok = "
+allocfuncnew {
+  getlocal 0
+  getlocal 1
+  add
+  return
+}
+nil
+allocnodenew {
+  bool true
+  je L1
+  int 1
+  j L2
+L1:
+  int 2
+L2:
+  int 3
+  getfunc 0
+  call 2
+  return
+}
+halt";
+    let t = t.verify(&assemble_insns(ok).unwrap(), &[]).unwrap();
+    assert_eq!(
+        t.funcs,
+        vec![FuncSig {
+            min_args: 2,
+            need: 4
+        }]
+    );
+    assert_eq!(t.nodes, vec![Some(8)]);
+    let upd = assemble_insns("savelast\nupdatenode 0\nsetnode 0\nhalt").unwrap();
+    assert!(t.verify(&[Insn::Halt], &upd).is_ok());
+
+    let err = |src: &str| t.verify(&assemble_insns(src).unwrap(), &[]).unwrap_err();
+    assert_eq!(
+        err("add\nhalt"),
+        VerifyErr::StackUnderflow(Location::Init(0))
+    );
+    assert_eq!(
+        err("int 1\nhalt"),
+        VerifyErr::BadDepthAtEnd(Location::Init(5), "halt", 1)
+    );
+    assert_eq!(
+        err("j8 1\nhalt"),
+        VerifyErr::BadJumpTarget(Location::Init(0), 3)
+    );
+    assert_eq!(err("nil"), VerifyErr::FallsOffEnd(Location::Init(0)));
+    assert_eq!(
+        err("bool true\nje L\nint 1\nL:\nallocdatanew\nhalt"),
+        VerifyErr::DepthMismatch(Location::Init(9), 1, 0)
+    );
+    assert_eq!(
+        err("getnode 1\nexit"),
+        VerifyErr::IndexOutOfRange(Location::Init(0), "node", 1)
+    );
+    assert_eq!(
+        err("int 1\ngetfunc 0\ncall 1\nexit"),
+        VerifyErr::TooFewArgs(Location::Init(5), 0, 2, 1)
+    );
+    assert!(matches!(
+        err("allocfunc 0 {\ngetfunc 0\ncall 0\nreturn\n}\nhalt"),
+        VerifyErr::RecursiveCall(..)
+    ));
+    let deep = format!("{}{}exit", "int 1\n".repeat(129), "add\n".repeat(128));
+    assert_eq!(err(&deep), VerifyErr::StackOverflow(129));
+}